@@ -0,0 +1,292 @@
+/*!
+ * File attributes, in a form that doesn't depend on the layout of the
+ * platform's `struct stat`.  They're converted to a `libc::stat` only when
+ * the reply is sent to the kernel.
+ */
+
+use std::libc::{dev_t, gid_t, mode_t, off_t, uid_t};
+use std::libc;
+use extra::time::Timespec;
+use ffi::fuse_ino_t;
+use stat::stat::arch::default_stat;
+
+// The S_IF* values are the same on every platform FUSE runs on, but libc
+// doesn't have all of them.  (In octal, which rust lacks, they're 0140000 for
+// sockets down to 0010000 for pipes.)
+static S_IFMT: mode_t = 0xf000;
+static S_IFSOCK: mode_t = 0xc000;
+static S_IFLNK: mode_t = 0xa000;
+static S_IFREG: mode_t = 0x8000;
+static S_IFBLK: mode_t = 0x6000;
+static S_IFDIR: mode_t = 0x4000;
+static S_IFCHR: mode_t = 0x2000;
+static S_IFIFO: mode_t = 0x1000;
+static S_ISUID: u16 = 0x800;
+static S_ISGID: u16 = 0x400;
+static S_ISVTX: u16 = 0x200;
+static PERMISSION_BITS: u16 = 0xfff;
+
+/// The type of a file, as given by the top bits of its mode
+#[deriving(Eq, Clone)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket
+}
+
+impl FileType {
+    /// The file type bits of a mode, or None if they aren't a known type
+    pub fn from_mode(mode: mode_t) -> Option<FileType> {
+        match mode & S_IFMT {
+            S_IFREG => Some(Regular),
+            S_IFDIR => Some(Directory),
+            S_IFLNK => Some(Symlink),
+            S_IFCHR => Some(CharDevice),
+            S_IFBLK => Some(BlockDevice),
+            S_IFIFO => Some(Fifo),
+            S_IFSOCK => Some(Socket),
+            _ => None
+        }
+    }
+
+    /// The file type bits to put in a mode
+    pub fn to_mode(&self) -> mode_t {
+        match *self {
+            Regular => S_IFREG,
+            Directory => S_IFDIR,
+            Symlink => S_IFLNK,
+            CharDevice => S_IFCHR,
+            BlockDevice => S_IFBLK,
+            Fifo => S_IFIFO,
+            Socket => S_IFSOCK
+        }
+    }
+}
+
+/**
+ * The permission bits of a mode: read, write and execute for the user, group
+ * and others, plus setuid, setgid and sticky.
+ */
+#[deriving(Eq, Clone)]
+pub struct Permissions {
+    priv bits: u16
+}
+
+impl Permissions {
+    /// The permissions in the low 12 bits of `bits`, which is what an octal
+    /// mode like 0755 is written as in C
+    pub fn from_bits(bits: u16) -> Permissions {
+        Permissions{bits: bits & PERMISSION_BITS}
+    }
+
+    /// The permission part of a full mode, leaving out the file type
+    pub fn from_mode(mode: mode_t) -> Permissions {
+        Permissions::from_bits(mode as u16)
+    }
+
+    /// Permissions from one octal digit each for the user, group and others,
+    /// so `from_rwx(7, 5, 5)` is 0755
+    pub fn from_rwx(user: u16, group: u16, other: u16) -> Permissions {
+        Permissions::from_bits(((user & 7) << 6) | ((group & 7) << 3) |
+                               (other & 7))
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.bits
+    }
+
+    /// The read/write/execute bits for the user, as one octal digit
+    pub fn user(&self) -> u16 {
+        (self.bits >> 6) & 7
+    }
+
+    /// The read/write/execute bits for the group, as one octal digit
+    pub fn group(&self) -> u16 {
+        (self.bits >> 3) & 7
+    }
+
+    /// The read/write/execute bits for others, as one octal digit
+    pub fn other(&self) -> u16 {
+        self.bits & 7
+    }
+
+    pub fn is_setuid(&self) -> bool {
+        self.bits & S_ISUID != 0
+    }
+
+    pub fn is_setgid(&self) -> bool {
+        self.bits & S_ISGID != 0
+    }
+
+    pub fn is_sticky(&self) -> bool {
+        self.bits & S_ISVTX != 0
+    }
+
+    pub fn with_setuid(&self, on: bool) -> Permissions {
+        self.with_bit(S_ISUID, on)
+    }
+
+    pub fn with_setgid(&self, on: bool) -> Permissions {
+        self.with_bit(S_ISGID, on)
+    }
+
+    pub fn with_sticky(&self, on: bool) -> Permissions {
+        self.with_bit(S_ISVTX, on)
+    }
+
+    fn with_bit(&self, bit: u16, on: bool) -> Permissions {
+        Permissions{bits: if on { self.bits | bit } else { self.bits & !bit }}
+    }
+}
+
+/**
+ * The attributes of a file, as returned by `getattr` and friends.  Start from
+ * `FileAttr::new` and fill in what's known:
+ *
+ * ~~~
+ * FileAttr{size: 42,
+ *          ..FileAttr::new(ino, Regular, Permissions::from_rwx(6, 4, 4))}
+ * ~~~
+ */
+#[deriving(Clone)]
+pub struct FileAttr {
+    ino: fuse_ino_t,
+    size: u64,
+    /// Number of 512-byte blocks allocated
+    blocks: u64,
+    atime: Timespec,
+    mtime: Timespec,
+    ctime: Timespec,
+    kind: FileType,
+    perm: Permissions,
+    nlink: u32,
+    uid: uid_t,
+    gid: gid_t,
+    /// The device, for a `CharDevice` or `BlockDevice`
+    rdev: dev_t,
+    /// The preferred size for I/O, or 0 to leave it to the kernel
+    blksize: u32
+}
+
+impl FileAttr {
+    /// Attributes of an empty file of the given type and permissions, with
+    /// one link, owned by root, and all of its times at the epoch
+    pub fn new(ino: fuse_ino_t, kind: FileType, perm: Permissions)
+               -> FileAttr {
+        FileAttr{
+            ino: ino,
+            size: 0,
+            blocks: 0,
+            atime: Timespec::new(0, 0),
+            mtime: Timespec::new(0, 0),
+            ctime: Timespec::new(0, 0),
+            kind: kind,
+            perm: perm,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0
+        }
+    }
+
+    /// The full mode: file type and permissions
+    pub fn mode(&self) -> mode_t {
+        self.kind.to_mode() | self.perm.bits() as mode_t
+    }
+
+    pub fn to_stat(&self) -> libc::stat {
+        let mut st = default_stat();
+        st.st_ino = self.ino as libc::ino_t;
+        st.st_size = self.size as off_t;
+        st.st_blocks = self.blocks as libc::blkcnt_t;
+        st.st_atime = self.atime.sec as libc::time_t;
+        st.st_atime_nsec = self.atime.nsec as libc::c_long;
+        st.st_mtime = self.mtime.sec as libc::time_t;
+        st.st_mtime_nsec = self.mtime.nsec as libc::c_long;
+        st.st_ctime = self.ctime.sec as libc::time_t;
+        st.st_ctime_nsec = self.ctime.nsec as libc::c_long;
+        st.st_mode = self.mode();
+        st.st_nlink = self.nlink as libc::nlink_t;
+        st.st_uid = self.uid;
+        st.st_gid = self.gid;
+        st.st_rdev = self.rdev;
+        st.st_blksize = self.blksize as libc::blksize_t;
+        st
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use extra::time::Timespec;
+
+    #[test]
+    fn file_type_round_trips_through_mode() {
+        let kinds = [Regular, Directory, Symlink, CharDevice, BlockDevice,
+                     Fifo, Socket];
+        for kind in kinds.iter() {
+            assert_eq!(FileType::from_mode(kind.to_mode() | 0x1ed),
+                       Some(*kind));
+        }
+    }
+
+    #[test]
+    fn unknown_file_type_is_none() {
+        assert_eq!(FileType::from_mode(0x1ed), None);
+        assert_eq!(FileType::from_mode(0xe000), None);
+    }
+
+    #[test]
+    fn to_stat_fills_in_the_attributes() {
+        let attr = FileAttr{
+            size: 42,
+            nlink: 3,
+            mtime: Timespec::new(1234, 5678),
+            .. FileAttr::new(7, Regular, Permissions::from_bits(0x1a4))
+        };
+        let st = attr.to_stat();
+        assert_eq!(st.st_ino as u64, 7);
+        assert_eq!(st.st_size as u64, 42);
+        assert_eq!(st.st_nlink as u32, 3);
+        // 0100644 in octal
+        assert_eq!(st.st_mode, 0x81a4);
+        assert_eq!(st.st_mtime as i64, 1234);
+        assert_eq!(st.st_mtime_nsec as i32, 5678);
+        assert_eq!(st.st_atime as i64, 0);
+    }
+
+    #[test]
+    fn from_rwx_packs_one_digit_each() {
+        let perm = Permissions::from_rwx(7, 5, 4);
+        // 0754 in octal
+        assert_eq!(perm.bits(), 0x1ec);
+        assert_eq!(perm.user(), 7);
+        assert_eq!(perm.group(), 5);
+        assert_eq!(perm.other(), 4);
+    }
+
+    #[test]
+    fn from_mode_drops_the_file_type() {
+        let perm = Permissions::from_mode(Directory.to_mode() | 0x1ed);
+        assert_eq!(perm, Permissions::from_rwx(7, 5, 5));
+    }
+
+    #[test]
+    fn special_bits_can_be_set_and_cleared() {
+        let perm = Permissions::from_rwx(7, 5, 5);
+        assert!(!perm.is_setuid() && !perm.is_setgid() && !perm.is_sticky());
+        let special = perm.with_setuid(true).with_setgid(true)
+            .with_sticky(true);
+        assert!(special.is_setuid() && special.is_setgid() &&
+                special.is_sticky());
+        // 07755 in octal
+        assert_eq!(special.bits(), 0xfed);
+        assert_eq!(special.with_setuid(false).with_setgid(false)
+                   .with_sticky(false), perm);
+    }
+}
@@ -0,0 +1,277 @@
+use std::libc::{c_int, c_uint, c_void, off_t, size_t};
+use std::libc;
+use std::ptr;
+use std::vec;
+use ffi::*;
+use lowlevel::ErrnoResult;
+use errno::Errno;
+
+pub use ffi::{FUSE_BUF_NO_SPLICE,FUSE_BUF_FORCE_SPLICE,FUSE_BUF_SPLICE_MOVE,
+              FUSE_BUF_SPLICE_NONBLOCK};
+
+/// One piece of a `BufVec`
+pub enum Buf {
+    /// Data held in memory
+    MemBuf(~[u8]),
+
+    /// The given number of bytes, to be read from a file descriptor starting
+    /// at the given offset, or at the descriptor's current position if there
+    /// is no offset (as for a pipe.)
+    FdBuf(c_int, Option<off_t>, size_t)
+}
+
+/**
+ * A sequence of buffers, each either in memory or behind a file descriptor,
+ * mirroring FUSE's `fuse_bufvec`.  Data behind a file descriptor can be moved
+ * to another file descriptor with `copy_to_fd` without passing through
+ * userspace, using splice where the kernel supports it.
+ *
+ * A `BufVec` may own some of the file descriptors in it (such as the pipe
+ * holding the data of a `write_buf` request), which are closed when it is
+ * dropped.  Reading data out of a pipe consumes it, so the methods that copy
+ * the data take the `BufVec` by value.
+ */
+pub struct BufVec {
+    priv bufs: ~[Buf],
+    priv owned_fds: ~[c_int]
+}
+
+impl BufVec {
+    /// Make a `BufVec` out of `bufs`.  Any file descriptors in them remain
+    /// owned by the caller.
+    pub fn new(bufs: ~[Buf]) -> BufVec {
+        BufVec{bufs: bufs, owned_fds: ~[]}
+    }
+
+    pub fn bufs<'a>(&'a self) -> &'a [Buf] {
+        let bufs: &'a [Buf] = self.bufs;
+        bufs
+    }
+
+    /// The total number of bytes in all of the buffers
+    pub fn size(&self) -> size_t {
+        let mut size = 0 as size_t;
+        for b in self.bufs.iter() {
+            size += buf_size(b);
+        }
+        size
+    }
+
+    /// Copy all of the data to `fd`, at `pos` if given or else at the
+    /// descriptor's current position.  `flags` is a combination of the
+    /// FUSE_BUF_* copy flags, which control whether splice is used.  Returns
+    /// the number of bytes copied, which is less than `size()` only if the
+    /// destination took a short write.
+    pub fn copy_to_fd(self, fd: c_int, pos: Option<off_t>, flags: c_uint)
+                      -> ErrnoResult<size_t> {
+        let mut total = 0 as size_t;
+        for b in self.bufs.iter() {
+            let size = buf_size(b);
+            let dst_pos = match pos {
+                Some(p) => Some(p + total as off_t),
+                None => None
+            };
+            let mut src = single_bufvec(c_buf(b));
+            match unsafe {
+                copy_bufvec(fd_c_buf(fd, dst_pos, size),
+                            ptr::to_mut_unsafe_ptr(&mut src), flags)
+            } {
+                Ok(copied) => {
+                    total += copied;
+                    if copied < size {
+                        break;
+                    }
+                },
+                Err(errno) => return Err(errno)
+            }
+        }
+        Ok(total)
+    }
+
+    /// Read all of the data into memory
+    pub fn into_bytes(self) -> ErrnoResult<~[u8]> {
+        let mut data: ~[u8] = vec::with_capacity(self.size() as uint);
+        for b in self.bufs.iter() {
+            match *b {
+                MemBuf(ref v) => data.push_all(*v),
+                FdBuf(_, _, size) => {
+                    let mut chunk = vec::from_elem(size as uint, 0u8);
+                    let mut src = single_bufvec(c_buf(b));
+                    match unsafe {
+                        copy_bufvec(mem_c_buf(chunk),
+                                    ptr::to_mut_unsafe_ptr(&mut src), 0)
+                    } {
+                        Ok(copied) => {
+                            chunk.truncate(copied as uint);
+                            data.push_all(chunk);
+                        },
+                        Err(errno) => return Err(errno)
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl Drop for BufVec {
+    #[fixed_stack_segment]
+    fn drop(&mut self) {
+        for fd in self.owned_fds.iter() {
+            unsafe { libc::close(*fd); }
+        }
+    }
+}
+
+/**
+ * Take the data out of a bufvec passed in by the C API.  Its buffers, and the
+ * pipe its data sits in when the kernel is splicing, are reused as soon as the
+ * callback that received it returns, so the data is moved into a pipe (or
+ * failing that, memory) owned by the new `BufVec`.  Moving spliced data from
+ * one pipe to another doesn't copy it.
+ */
+#[fixed_stack_segment]
+pub unsafe fn take_from_c(bufv: *mut Struct_fuse_bufvec)
+                          -> ErrnoResult<BufVec> {
+    let size = c_bufvec_remaining(bufv);
+    if c_bufvec_has_fd(bufv) {
+        match pipe_with_capacity(size) {
+            Some((read_fd, write_fd)) => {
+                let result = copy_bufvec(fd_c_buf(write_fd, None, size), bufv,
+                                         FUSE_BUF_SPLICE_MOVE);
+                libc::close(write_fd);
+                return match result {
+                    Ok(copied) => Ok(BufVec{
+                            bufs: ~[FdBuf(read_fd, None, copied)],
+                            owned_fds: ~[read_fd]
+                        }),
+                    Err(errno) => {
+                        libc::close(read_fd);
+                        Err(errno)
+                    }
+                };
+            },
+            None => ()
+        }
+    }
+    let mut data = vec::from_elem(size as uint, 0u8);
+    if size > 0 {
+        match copy_bufvec(mem_c_buf(data), bufv, 0) {
+            Ok(copied) => data.truncate(copied as uint),
+            Err(errno) => return Err(errno)
+        }
+    }
+    Ok(BufVec::new(~[MemBuf(data)]))
+}
+
+/// A one-buffer C bufvec pointing at the data in `b`, which has to outlive it
+pub fn c_bufvec_for(b: &Buf) -> Struct_fuse_bufvec {
+    single_bufvec(c_buf(b))
+}
+
+/// A one-buffer C bufvec pointing at `data`, which has to outlive it
+pub fn c_bufvec_for_mem(data: &[u8]) -> Struct_fuse_bufvec {
+    single_bufvec(mem_c_buf(data))
+}
+
+fn buf_size(b: &Buf) -> size_t {
+    match *b {
+        MemBuf(ref v) => v.len() as size_t,
+        FdBuf(_, _, size) => size
+    }
+}
+
+fn mem_c_buf(v: &[u8]) -> Struct_fuse_buf {
+    Struct_fuse_buf {
+        size: v.len() as size_t,
+        flags: 0,
+        mem: vec::raw::to_ptr(v) as *mut c_void,
+        fd: -1,
+        pos: 0
+    }
+}
+
+fn fd_c_buf(fd: c_int, pos: Option<off_t>, size: size_t) -> Struct_fuse_buf {
+    Struct_fuse_buf {
+        size: size,
+        flags: match pos {
+            Some(_) => FUSE_BUF_IS_FD | FUSE_BUF_FD_SEEK,
+            None => FUSE_BUF_IS_FD
+        },
+        mem: ptr::mut_null(),
+        fd: fd,
+        pos: pos.unwrap_or(0)
+    }
+}
+
+fn c_buf(b: &Buf) -> Struct_fuse_buf {
+    match *b {
+        MemBuf(ref v) => mem_c_buf(*v),
+        FdBuf(fd, pos, size) => fd_c_buf(fd, pos, size)
+    }
+}
+
+fn single_bufvec(b: Struct_fuse_buf) -> Struct_fuse_bufvec {
+    Struct_fuse_bufvec {
+        count: 1,
+        idx: 0,
+        off: 0,
+        buf: [b]
+    }
+}
+
+#[fixed_stack_segment]
+unsafe fn copy_bufvec(dst: Struct_fuse_buf, src: *mut Struct_fuse_bufvec,
+                      flags: c_uint) -> ErrnoResult<size_t> {
+    let mut dstv = single_bufvec(dst);
+    let copied = fuse_buf_copy(ptr::to_mut_unsafe_ptr(&mut dstv), src, flags);
+    if copied < 0 {
+        Err(Errno::from_raw(-copied as c_int))
+    } else {
+        Ok(copied as size_t)
+    }
+}
+
+// The bufs array is declared with one element, but really has `count` of them
+unsafe fn c_bufvec_buf(bufv: *Struct_fuse_bufvec, i: size_t)
+                       -> *Struct_fuse_buf {
+    ptr::offset(ptr::to_unsafe_ptr(&(*bufv).buf[0]), i as int)
+}
+
+unsafe fn c_bufvec_remaining(bufv: *mut Struct_fuse_bufvec) -> size_t {
+    let mut size = 0 as size_t;
+    for i in range((*bufv).idx, (*bufv).count) {
+        size += (*c_bufvec_buf(bufv as *Struct_fuse_bufvec, i)).size;
+    }
+    size - (*bufv).off
+}
+
+unsafe fn c_bufvec_has_fd(bufv: *mut Struct_fuse_bufvec) -> bool {
+    range((*bufv).idx, (*bufv).count).any(|i| {
+        (*c_bufvec_buf(bufv as *Struct_fuse_bufvec, i)).flags & FUSE_BUF_IS_FD
+            != 0
+    })
+}
+
+// Nothing reads from the pipe while we fill it, so it has to be big enough
+// to hold all of the data at once.
+#[cfg(target_os = "linux")]
+#[fixed_stack_segment]
+unsafe fn pipe_with_capacity(size: size_t) -> Option<(c_int, c_int)> {
+    let mut fds = [0 as c_int, ..2];
+    if libc::pipe(ptr::to_mut_unsafe_ptr(&mut fds[0])) != 0 {
+        return None;
+    }
+    if fcntl_int(fds[1], F_SETPIPE_SZ, size as c_int) < size as c_int {
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+        None
+    } else {
+        Some((fds[0], fds[1]))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn pipe_with_capacity(_size: size_t) -> Option<(c_int, c_int)> {
+    None
+}
@@ -0,0 +1,417 @@
+/*!
+ * Error numbers for filesystem operations to return.  Use the named values
+ * here rather than raw numbers; any `Errno` that isn't valid for the platform
+ * is replaced with EIO before it is sent to the kernel.
+ */
+
+use std::libc::c_int;
+use std::libc;
+use std::os;
+use std::str;
+use std::rt::io;
+use std::rt::io::{IoError, io_error};
+
+pub use self::consts::*;
+
+/// A positive errno value, as passed to `fuse_reply_err`
+#[deriving(Eq, Clone)]
+pub struct Errno {
+    priv code: c_int
+}
+
+impl Errno {
+    /// Wrap a raw errno value.  Nothing checks it here, but an invalid one
+    /// (zero, negative, or unknown to the platform) is turned into EIO before
+    /// it reaches the kernel.
+    pub fn from_raw(code: c_int) -> Errno {
+        Errno{code: code}
+    }
+
+    /// The errno left by the last failed libc call on this thread
+    pub fn last() -> Errno {
+        Errno{code: os::errno() as c_int}
+    }
+
+    /**
+     * The closest errno to a rust I/O error.  `FileNotFound` and
+     * `PathDoesntExist` become ENOENT, `PathAlreadyExists` EEXIST, and
+     * `PermissionDenied`, `ConnectionRefused`, `ConnectionReset` and
+     * `BrokenPipe` have an errno of their own; every other kind, including
+     * `EndOfFile`, `Closed`, `ConnectionFailed`,
+     * `MismatchedFileTypeForOperation`, `PreviousIoError` and `OtherIoError`,
+     * becomes EIO, since the errno behind them is lost.
+     */
+    pub fn from_io_error(err: &IoError) -> Errno {
+        match err.kind {
+            io::FileNotFound | io::PathDoesntExist => ENOENT,
+            io::PathAlreadyExists => EEXIST,
+            io::PermissionDenied => EACCES,
+            io::ConnectionRefused => ECONNREFUSED,
+            io::ConnectionReset => ECONNRESET,
+            io::BrokenPipe => EPIPE,
+            _ => EIO
+        }
+    }
+
+    pub fn to_raw(&self) -> c_int {
+        self.code
+    }
+
+    /// Whether the platform defines this errno
+    pub fn is_valid(&self) -> bool {
+        self.code > 0 && self.code <= LAST_ERRNO &&
+            !UNUSED_ERRNOS.contains(&self.code)
+    }
+}
+
+impl ToStr for Errno {
+    #[fixed_stack_segment]
+    fn to_str(&self) -> ~str {
+        let desc = unsafe {
+            str::raw::from_c_str(libc::strerror(self.code))
+        };
+        format!("{:s} (errno {:d})", desc, self.code as int)
+    }
+}
+
+/**
+ * Run `f`, turning any I/O error it raises into an errno.  Only the first error
+ * raised is kept; as with any trapped `io_error`, the I/O call that raised it
+ * returns a placeholder value and `f` carries on, so `f` should check for
+ * errors itself if it goes on to do anything that matters afterwards.
+ */
+pub fn trap_io_error<T>(f: &fn() -> T) -> Result<T, Errno> {
+    let mut first_error = None;
+    let result = do io_error::cond.trap(|e| {
+        if first_error.is_none() {
+            first_error = Some(Errno::from_io_error(&e));
+        }
+    }).inside {
+        f()
+    };
+    match first_error {
+        Some(errno) => Err(errno),
+        None => Ok(result)
+    }
+}
+
+// Numbers up to LAST_ERRNO that the platform doesn't give to any error
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+static UNUSED_ERRNOS: &'static [c_int] = &[41, 58];
+
+#[cfg(target_os = "macos")]
+static UNUSED_ERRNOS: &'static [c_int] = &[];
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+pub mod consts {
+    use std::libc::c_int;
+    use super::Errno;
+
+    pub static EPERM: Errno = Errno{code: 1};
+    pub static ENOENT: Errno = Errno{code: 2};
+    pub static ESRCH: Errno = Errno{code: 3};
+    pub static EINTR: Errno = Errno{code: 4};
+    pub static EIO: Errno = Errno{code: 5};
+    pub static ENXIO: Errno = Errno{code: 6};
+    pub static E2BIG: Errno = Errno{code: 7};
+    pub static ENOEXEC: Errno = Errno{code: 8};
+    pub static EBADF: Errno = Errno{code: 9};
+    pub static ECHILD: Errno = Errno{code: 10};
+    pub static EAGAIN: Errno = Errno{code: 11};
+    pub static ENOMEM: Errno = Errno{code: 12};
+    pub static EACCES: Errno = Errno{code: 13};
+    pub static EFAULT: Errno = Errno{code: 14};
+    pub static ENOTBLK: Errno = Errno{code: 15};
+    pub static EBUSY: Errno = Errno{code: 16};
+    pub static EEXIST: Errno = Errno{code: 17};
+    pub static EXDEV: Errno = Errno{code: 18};
+    pub static ENODEV: Errno = Errno{code: 19};
+    pub static ENOTDIR: Errno = Errno{code: 20};
+    pub static EISDIR: Errno = Errno{code: 21};
+    pub static EINVAL: Errno = Errno{code: 22};
+    pub static ENFILE: Errno = Errno{code: 23};
+    pub static EMFILE: Errno = Errno{code: 24};
+    pub static ENOTTY: Errno = Errno{code: 25};
+    pub static ETXTBSY: Errno = Errno{code: 26};
+    pub static EFBIG: Errno = Errno{code: 27};
+    pub static ENOSPC: Errno = Errno{code: 28};
+    pub static ESPIPE: Errno = Errno{code: 29};
+    pub static EROFS: Errno = Errno{code: 30};
+    pub static EMLINK: Errno = Errno{code: 31};
+    pub static EPIPE: Errno = Errno{code: 32};
+    pub static EDOM: Errno = Errno{code: 33};
+    pub static ERANGE: Errno = Errno{code: 34};
+    pub static EDEADLK: Errno = Errno{code: 35};
+    pub static ENAMETOOLONG: Errno = Errno{code: 36};
+    pub static ENOLCK: Errno = Errno{code: 37};
+    pub static ENOSYS: Errno = Errno{code: 38};
+    pub static ENOTEMPTY: Errno = Errno{code: 39};
+    pub static ELOOP: Errno = Errno{code: 40};
+    pub static ENOMSG: Errno = Errno{code: 42};
+    pub static EIDRM: Errno = Errno{code: 43};
+    pub static ECHRNG: Errno = Errno{code: 44};
+    pub static EL2NSYNC: Errno = Errno{code: 45};
+    pub static EL3HLT: Errno = Errno{code: 46};
+    pub static EL3RST: Errno = Errno{code: 47};
+    pub static ELNRNG: Errno = Errno{code: 48};
+    pub static EUNATCH: Errno = Errno{code: 49};
+    pub static ENOCSI: Errno = Errno{code: 50};
+    pub static EL2HLT: Errno = Errno{code: 51};
+    pub static EBADE: Errno = Errno{code: 52};
+    pub static EBADR: Errno = Errno{code: 53};
+    pub static EXFULL: Errno = Errno{code: 54};
+    pub static ENOANO: Errno = Errno{code: 55};
+    pub static EBADRQC: Errno = Errno{code: 56};
+    pub static EBADSLT: Errno = Errno{code: 57};
+    pub static EBFONT: Errno = Errno{code: 59};
+    pub static ENOSTR: Errno = Errno{code: 60};
+    pub static ENODATA: Errno = Errno{code: 61};
+    pub static ETIME: Errno = Errno{code: 62};
+    pub static ENOSR: Errno = Errno{code: 63};
+    pub static ENONET: Errno = Errno{code: 64};
+    pub static ENOPKG: Errno = Errno{code: 65};
+    pub static EREMOTE: Errno = Errno{code: 66};
+    pub static ENOLINK: Errno = Errno{code: 67};
+    pub static EADV: Errno = Errno{code: 68};
+    pub static ESRMNT: Errno = Errno{code: 69};
+    pub static ECOMM: Errno = Errno{code: 70};
+    pub static EPROTO: Errno = Errno{code: 71};
+    pub static EMULTIHOP: Errno = Errno{code: 72};
+    pub static EDOTDOT: Errno = Errno{code: 73};
+    pub static EBADMSG: Errno = Errno{code: 74};
+    pub static EOVERFLOW: Errno = Errno{code: 75};
+    pub static ENOTUNIQ: Errno = Errno{code: 76};
+    pub static EBADFD: Errno = Errno{code: 77};
+    pub static EREMCHG: Errno = Errno{code: 78};
+    pub static ELIBACC: Errno = Errno{code: 79};
+    pub static ELIBBAD: Errno = Errno{code: 80};
+    pub static ELIBSCN: Errno = Errno{code: 81};
+    pub static ELIBMAX: Errno = Errno{code: 82};
+    pub static ELIBEXEC: Errno = Errno{code: 83};
+    pub static EILSEQ: Errno = Errno{code: 84};
+    pub static ERESTART: Errno = Errno{code: 85};
+    pub static ESTRPIPE: Errno = Errno{code: 86};
+    pub static EUSERS: Errno = Errno{code: 87};
+    pub static ENOTSOCK: Errno = Errno{code: 88};
+    pub static EDESTADDRREQ: Errno = Errno{code: 89};
+    pub static EMSGSIZE: Errno = Errno{code: 90};
+    pub static EPROTOTYPE: Errno = Errno{code: 91};
+    pub static ENOPROTOOPT: Errno = Errno{code: 92};
+    pub static EPROTONOSUPPORT: Errno = Errno{code: 93};
+    pub static ESOCKTNOSUPPORT: Errno = Errno{code: 94};
+    pub static EOPNOTSUPP: Errno = Errno{code: 95};
+    pub static EPFNOSUPPORT: Errno = Errno{code: 96};
+    pub static EAFNOSUPPORT: Errno = Errno{code: 97};
+    pub static EADDRINUSE: Errno = Errno{code: 98};
+    pub static EADDRNOTAVAIL: Errno = Errno{code: 99};
+    pub static ENETDOWN: Errno = Errno{code: 100};
+    pub static ENETUNREACH: Errno = Errno{code: 101};
+    pub static ENETRESET: Errno = Errno{code: 102};
+    pub static ECONNABORTED: Errno = Errno{code: 103};
+    pub static ECONNRESET: Errno = Errno{code: 104};
+    pub static ENOBUFS: Errno = Errno{code: 105};
+    pub static EISCONN: Errno = Errno{code: 106};
+    pub static ENOTCONN: Errno = Errno{code: 107};
+    pub static ESHUTDOWN: Errno = Errno{code: 108};
+    pub static ETOOMANYREFS: Errno = Errno{code: 109};
+    pub static ETIMEDOUT: Errno = Errno{code: 110};
+    pub static ECONNREFUSED: Errno = Errno{code: 111};
+    pub static EHOSTDOWN: Errno = Errno{code: 112};
+    pub static EHOSTUNREACH: Errno = Errno{code: 113};
+    pub static EALREADY: Errno = Errno{code: 114};
+    pub static EINPROGRESS: Errno = Errno{code: 115};
+    pub static ESTALE: Errno = Errno{code: 116};
+    pub static EUCLEAN: Errno = Errno{code: 117};
+    pub static ENOTNAM: Errno = Errno{code: 118};
+    pub static ENAVAIL: Errno = Errno{code: 119};
+    pub static EISNAM: Errno = Errno{code: 120};
+    pub static EREMOTEIO: Errno = Errno{code: 121};
+    pub static EDQUOT: Errno = Errno{code: 122};
+    pub static ENOMEDIUM: Errno = Errno{code: 123};
+    pub static EMEDIUMTYPE: Errno = Errno{code: 124};
+    pub static ECANCELED: Errno = Errno{code: 125};
+    pub static ENOKEY: Errno = Errno{code: 126};
+    pub static EKEYEXPIRED: Errno = Errno{code: 127};
+    pub static EKEYREVOKED: Errno = Errno{code: 128};
+    pub static EKEYREJECTED: Errno = Errno{code: 129};
+    pub static EOWNERDEAD: Errno = Errno{code: 130};
+    pub static ENOTRECOVERABLE: Errno = Errno{code: 131};
+    pub static ERFKILL: Errno = Errno{code: 132};
+    pub static EHWPOISON: Errno = Errno{code: 133};
+
+    // Another name for EAGAIN
+    pub static EWOULDBLOCK: Errno = Errno{code: 11};
+    // Another name for EDEADLK
+    pub static EDEADLOCK: Errno = Errno{code: 35};
+    // Another name for EOPNOTSUPP
+    pub static ENOTSUP: Errno = Errno{code: 95};
+    // What getxattr and removexattr return for a missing attribute
+    pub static ENOATTR: Errno = Errno{code: 61};
+
+    pub static LAST_ERRNO: c_int = 133;
+}
+
+#[cfg(target_os = "macos")]
+pub mod consts {
+    use std::libc::c_int;
+    use super::Errno;
+
+    pub static EPERM: Errno = Errno{code: 1};
+    pub static ENOENT: Errno = Errno{code: 2};
+    pub static ESRCH: Errno = Errno{code: 3};
+    pub static EINTR: Errno = Errno{code: 4};
+    pub static EIO: Errno = Errno{code: 5};
+    pub static ENXIO: Errno = Errno{code: 6};
+    pub static E2BIG: Errno = Errno{code: 7};
+    pub static ENOEXEC: Errno = Errno{code: 8};
+    pub static EBADF: Errno = Errno{code: 9};
+    pub static ECHILD: Errno = Errno{code: 10};
+    pub static EDEADLK: Errno = Errno{code: 11};
+    pub static ENOMEM: Errno = Errno{code: 12};
+    pub static EACCES: Errno = Errno{code: 13};
+    pub static EFAULT: Errno = Errno{code: 14};
+    pub static ENOTBLK: Errno = Errno{code: 15};
+    pub static EBUSY: Errno = Errno{code: 16};
+    pub static EEXIST: Errno = Errno{code: 17};
+    pub static EXDEV: Errno = Errno{code: 18};
+    pub static ENODEV: Errno = Errno{code: 19};
+    pub static ENOTDIR: Errno = Errno{code: 20};
+    pub static EISDIR: Errno = Errno{code: 21};
+    pub static EINVAL: Errno = Errno{code: 22};
+    pub static ENFILE: Errno = Errno{code: 23};
+    pub static EMFILE: Errno = Errno{code: 24};
+    pub static ENOTTY: Errno = Errno{code: 25};
+    pub static ETXTBSY: Errno = Errno{code: 26};
+    pub static EFBIG: Errno = Errno{code: 27};
+    pub static ENOSPC: Errno = Errno{code: 28};
+    pub static ESPIPE: Errno = Errno{code: 29};
+    pub static EROFS: Errno = Errno{code: 30};
+    pub static EMLINK: Errno = Errno{code: 31};
+    pub static EPIPE: Errno = Errno{code: 32};
+    pub static EDOM: Errno = Errno{code: 33};
+    pub static ERANGE: Errno = Errno{code: 34};
+    pub static EAGAIN: Errno = Errno{code: 35};
+    pub static EINPROGRESS: Errno = Errno{code: 36};
+    pub static EALREADY: Errno = Errno{code: 37};
+    pub static ENOTSOCK: Errno = Errno{code: 38};
+    pub static EDESTADDRREQ: Errno = Errno{code: 39};
+    pub static EMSGSIZE: Errno = Errno{code: 40};
+    pub static EPROTOTYPE: Errno = Errno{code: 41};
+    pub static ENOPROTOOPT: Errno = Errno{code: 42};
+    pub static EPROTONOSUPPORT: Errno = Errno{code: 43};
+    pub static ESOCKTNOSUPPORT: Errno = Errno{code: 44};
+    pub static ENOTSUP: Errno = Errno{code: 45};
+    pub static EPFNOSUPPORT: Errno = Errno{code: 46};
+    pub static EAFNOSUPPORT: Errno = Errno{code: 47};
+    pub static EADDRINUSE: Errno = Errno{code: 48};
+    pub static EADDRNOTAVAIL: Errno = Errno{code: 49};
+    pub static ENETDOWN: Errno = Errno{code: 50};
+    pub static ENETUNREACH: Errno = Errno{code: 51};
+    pub static ENETRESET: Errno = Errno{code: 52};
+    pub static ECONNABORTED: Errno = Errno{code: 53};
+    pub static ECONNRESET: Errno = Errno{code: 54};
+    pub static ENOBUFS: Errno = Errno{code: 55};
+    pub static EISCONN: Errno = Errno{code: 56};
+    pub static ENOTCONN: Errno = Errno{code: 57};
+    pub static ESHUTDOWN: Errno = Errno{code: 58};
+    pub static ETOOMANYREFS: Errno = Errno{code: 59};
+    pub static ETIMEDOUT: Errno = Errno{code: 60};
+    pub static ECONNREFUSED: Errno = Errno{code: 61};
+    pub static ELOOP: Errno = Errno{code: 62};
+    pub static ENAMETOOLONG: Errno = Errno{code: 63};
+    pub static EHOSTDOWN: Errno = Errno{code: 64};
+    pub static EHOSTUNREACH: Errno = Errno{code: 65};
+    pub static ENOTEMPTY: Errno = Errno{code: 66};
+    pub static EPROCLIM: Errno = Errno{code: 67};
+    pub static EUSERS: Errno = Errno{code: 68};
+    pub static EDQUOT: Errno = Errno{code: 69};
+    pub static ESTALE: Errno = Errno{code: 70};
+    pub static EREMOTE: Errno = Errno{code: 71};
+    pub static EBADRPC: Errno = Errno{code: 72};
+    pub static ERPCMISMATCH: Errno = Errno{code: 73};
+    pub static EPROGUNAVAIL: Errno = Errno{code: 74};
+    pub static EPROGMISMATCH: Errno = Errno{code: 75};
+    pub static EPROCUNAVAIL: Errno = Errno{code: 76};
+    pub static ENOLCK: Errno = Errno{code: 77};
+    pub static ENOSYS: Errno = Errno{code: 78};
+    pub static EFTYPE: Errno = Errno{code: 79};
+    pub static EAUTH: Errno = Errno{code: 80};
+    pub static ENEEDAUTH: Errno = Errno{code: 81};
+    pub static EPWROFF: Errno = Errno{code: 82};
+    pub static EDEVERR: Errno = Errno{code: 83};
+    pub static EOVERFLOW: Errno = Errno{code: 84};
+    pub static EBADEXEC: Errno = Errno{code: 85};
+    pub static EBADARCH: Errno = Errno{code: 86};
+    pub static ESHLIBVERS: Errno = Errno{code: 87};
+    pub static EBADMACHO: Errno = Errno{code: 88};
+    pub static ECANCELED: Errno = Errno{code: 89};
+    pub static EIDRM: Errno = Errno{code: 90};
+    pub static ENOMSG: Errno = Errno{code: 91};
+    pub static EILSEQ: Errno = Errno{code: 92};
+    pub static ENOATTR: Errno = Errno{code: 93};
+    pub static EBADMSG: Errno = Errno{code: 94};
+    pub static EMULTIHOP: Errno = Errno{code: 95};
+    pub static ENODATA: Errno = Errno{code: 96};
+    pub static ENOLINK: Errno = Errno{code: 97};
+    pub static ENOSR: Errno = Errno{code: 98};
+    pub static ENOSTR: Errno = Errno{code: 99};
+    pub static EPROTO: Errno = Errno{code: 100};
+    pub static ETIME: Errno = Errno{code: 101};
+    pub static EOPNOTSUPP: Errno = Errno{code: 102};
+    pub static ENOPOLICY: Errno = Errno{code: 103};
+    pub static ENOTRECOVERABLE: Errno = Errno{code: 104};
+    pub static EOWNERDEAD: Errno = Errno{code: 105};
+    pub static EQFULL: Errno = Errno{code: 106};
+
+    // Another name for EAGAIN
+    pub static EWOULDBLOCK: Errno = Errno{code: 35};
+
+    pub static LAST_ERRNO: c_int = 106;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rt::io;
+    use std::rt::io::{IoError, IoErrorKind};
+
+    fn io_error(kind: IoErrorKind) -> IoError {
+        IoError{kind: kind, desc: "test", detail: None}
+    }
+
+    #[test]
+    fn io_errors_keep_their_errno() {
+        assert_eq!(Errno::from_io_error(&io_error(io::FileNotFound)), ENOENT);
+        assert_eq!(Errno::from_io_error(&io_error(io::PathDoesntExist)),
+                   ENOENT);
+        assert_eq!(Errno::from_io_error(&io_error(io::PathAlreadyExists)),
+                   EEXIST);
+        assert_eq!(Errno::from_io_error(&io_error(io::PermissionDenied)),
+                   EACCES);
+        assert_eq!(Errno::from_io_error(&io_error(io::BrokenPipe)), EPIPE);
+    }
+
+    #[test]
+    fn io_errors_without_an_errno_are_eio() {
+        assert_eq!(Errno::from_io_error(&io_error(io::EndOfFile)), EIO);
+        assert_eq!(Errno::from_io_error(&io_error(io::OtherIoError)), EIO);
+    }
+
+    #[test]
+    fn valid_errnos() {
+        assert!(ENOENT.is_valid());
+        assert!(Errno::from_raw(LAST_ERRNO).is_valid());
+        assert!(!Errno::from_raw(0).is_valid());
+        assert!(!Errno::from_raw(-2).is_valid());
+        assert!(!Errno::from_raw(LAST_ERRNO + 1).is_valid());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn unused_linux_errnos_are_invalid() {
+        assert!(!Errno::from_raw(41).is_valid());
+        assert!(!Errno::from_raw(58).is_valid());
+    }
+}
@@ -4,7 +4,7 @@
  *   * Replace Struct_stat with libc::stat
  *   * use a bindgen'd statvfs and statfs
  *   * add Struct_iovec
- *   * add stub Struct_flock
+ *   * add Struct_flock (the layout differs between linux and OS X)
  *   * add derived Default impl for Struct_fuse_file_info
  *   * wrap the type of the function pointers in Option to make them nullable
  *   * Switch the link args for use with osxfuse
@@ -15,7 +15,74 @@ use std::libc::*;
 pub type Struct_stat = stat;
 mod statfs;
 type Struct_statfs = statfs::Struct_statfs;
-pub type Struct_flock = c_void; // This is not implemented and only used for a pointer anyway...
+
+#[cfg(target_os = "linux")]
+pub struct Struct_flock {
+    l_type: c_short,
+    l_whence: c_short,
+    l_start: off_t,
+    l_len: off_t,
+    l_pid: pid_t,
+}
+#[cfg(target_os = "linux")]
+pub static F_RDLCK: c_short = 0;
+#[cfg(target_os = "linux")]
+pub static F_WRLCK: c_short = 1;
+#[cfg(target_os = "linux")]
+pub static F_UNLCK: c_short = 2;
+
+#[cfg(target_os = "macos")]
+pub struct Struct_flock {
+    l_start: off_t,
+    l_len: off_t,
+    l_pid: pid_t,
+    l_type: c_short,
+    l_whence: c_short,
+}
+#[cfg(target_os = "macos")]
+pub static F_RDLCK: c_short = 1;
+#[cfg(target_os = "macos")]
+pub static F_UNLCK: c_short = 2;
+#[cfg(target_os = "macos")]
+pub static F_WRLCK: c_short = 3;
+
+#[cfg(target_os = "linux")]
+pub static F_SETPIPE_SZ: c_int = 1031;
+
+#[cfg(target_os = "linux")]
+pub type pthread_t = c_ulong;
+#[cfg(target_os = "linux")]
+pub static SIGUSR1: c_int = 10;
+#[cfg(target_os = "macos")]
+pub type pthread_t = *c_void;
+#[cfg(target_os = "macos")]
+pub static SIGUSR1: c_int = 30;
+
+// Open flags that libc lacks (the octal values are written in hex)
+#[cfg(target_os = "linux")]
+pub static O_DSYNC: c_int = 0x1000;
+#[cfg(target_os = "linux")]
+pub static O_SYNC: c_int = 0x101000;
+#[cfg(target_os = "linux")]
+pub static O_NOATIME: c_int = 0x40000;
+#[cfg(target_os = "linux", not(target_arch = "arm"))]
+pub static O_DIRECT: c_int = 0x4000;
+#[cfg(target_os = "linux", target_arch = "arm")]
+pub static O_DIRECT: c_int = 0x10000;
+#[cfg(target_os = "macos")]
+pub static O_DSYNC: c_int = 0x400000;
+#[cfg(target_os = "macos")]
+pub static O_SYNC: c_int = 0x80;
+// OS X has neither of these, so they never show up in the flags
+#[cfg(target_os = "macos")]
+pub static O_NOATIME: c_int = 0;
+#[cfg(target_os = "macos")]
+pub static O_DIRECT: c_int = 0;
+
+pub static LOCK_SH: c_int = 1;
+pub static LOCK_EX: c_int = 2;
+pub static LOCK_NB: c_int = 4;
+pub static LOCK_UN: c_int = 8;
 
 pub type __fsblkcnt64_t = c_ulong;
 pub type __fsfilcnt64_t = c_ulong;
@@ -101,6 +168,10 @@ pub struct Struct_fuse_bufvec {
     off: size_t,
     buf: [Struct_fuse_buf, ..1u],
 }
+pub static FUSE_IOCTL_COMPAT: c_uint = 1 << 0;
+pub static FUSE_IOCTL_UNRESTRICTED: c_uint = 1 << 1;
+pub static FUSE_IOCTL_RETRY: c_uint = 1 << 2;
+pub static FUSE_IOCTL_DIR: c_uint = 1 << 4;
 pub type fuse_ino_t = c_ulong;
 pub type Struct_fuse_req = c_void;
 pub type fuse_req_t = *mut Struct_fuse_req;
@@ -123,7 +194,7 @@ pub struct Struct_fuse_forget_data {
 }
 pub struct Struct_fuse_lowlevel_ops {
     init: Option<extern "C" fn(userdata:*mut c_void, 
-                               _conn:*Struct_fuse_conn_info)>,
+                               conn:*mut Struct_fuse_conn_info)>,
     destroy: Option<extern "C" fn(userdata:*mut c_void)>,
     lookup: Option<extern "C" fn(req:fuse_req_t,  parent:fuse_ino_t, 
                                  name:*c_schar)>,
@@ -220,7 +291,8 @@ pub struct Struct_fuse_lowlevel_ops {
                (arg1: fuse_req_t, arg2: fuse_ino_t,
                 arg3: *mut Struct_fuse_file_info, arg4: c_int)>,
 }
-pub type fuse_interrupt_func_t = *u8;
+pub type fuse_interrupt_func_t = Option<extern "C" fn(req: fuse_req_t,
+                                                    data: *mut c_void)>;
 pub struct Struct_fuse_session_ops {
     process: *u8,
     exit: *u8,
@@ -242,6 +314,21 @@ extern "C" {}
 #[link_args = "-losxfuse"]
 extern "C" {}
 
+// Unmounting without going through the C API, which can't be told not to
+// unmount a second time
+#[cfg(target_os = "linux")]
+pub static MNT_DETACH: c_int = 2;
+#[cfg(target_os = "linux")]
+extern "C" {
+    pub fn umount2(target: *c_schar, flags: c_int) -> c_int;
+}
+#[cfg(target_os = "macos")]
+pub static MNT_FORCE: c_int = 0x80000;
+#[cfg(target_os = "macos")]
+extern "C" {
+    pub fn unmount(target: *c_schar, flags: c_int) -> c_int;
+}
+
 extern "C" {
     pub fn fuse_opt_parse(args: *mut Struct_fuse_args, data: *mut c_void,
                           opts: *Struct_fuse_opt, proc: fuse_opt_proc_t) ->
@@ -376,4 +463,14 @@ extern "C" {
     pub fn fuse_chan_send(ch: *mut Struct_fuse_chan, iov: *Struct_iovec,
                           count: size_t) -> c_int;
     pub fn fuse_chan_destroy(ch: *mut Struct_fuse_chan);
+
+    pub fn pthread_self() -> pthread_t;
+    pub fn pthread_kill(thread: pthread_t, sig: c_int) -> c_int;
+    // The handler is a sighandler_t: a function pointer, or SIG_DFL/SIG_IGN
+    pub fn signal(sig: c_int, handler: *c_void) -> *c_void;
+    pub fn siginterrupt(sig: c_int, flag: c_int) -> c_int;
+
+    // libc's fcntl is variadic; this is the form that takes an int argument
+    #[link_name = "fcntl"]
+    pub fn fcntl_int(fd: c_int, cmd: c_int, arg: c_int) -> c_int;
 }
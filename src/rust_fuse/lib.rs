@@ -5,5 +5,9 @@
 extern mod extra;
 
 pub mod lowlevel;
+pub mod name;
+pub mod attr;
+pub mod buf;
+pub mod errno;
 pub mod ffi;
 pub mod stat;
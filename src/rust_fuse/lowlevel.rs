@@ -2,6 +2,7 @@ use std::libc::{
     c_double,
     c_int,
     c_schar,
+    c_short,
     c_uint,
     c_ulong,
     c_void,
@@ -9,10 +10,21 @@ use std::libc::{
     gid_t,
     mode_t,
     off_t,
+    pid_t,
     size_t,
-    time_t,
     uid_t,
-    EIO
+    R_OK,
+    O_APPEND,
+    O_CREAT,
+    O_EXCL,
+    O_RDONLY,
+    O_RDWR,
+    O_TRUNC,
+    O_WRONLY,
+    SEEK_SET,
+    S_IFDIR,
+    S_IFMT,
+    W_OK
 };
 use std::sys::size_of;
 use std::cast::transmute;
@@ -27,40 +39,171 @@ use std::cmp;
 use std::iter::AdditiveIterator;
 use ffi::*;
 use super::stat::stat::arch::default_stat;
+use buf;
+use attr::{FileAttr,FileType,Permissions};
+use name::FileName;
+use errno::{Errno, EACCES, EINTR, EINVAL, EIO, ENODEV, ENOENT, ENOSYS,
+            ENOTCONN, ENOTDIR, EPERM};
 use std::libc;
 use std::util::NonCopyable;
 use std::cell::Cell;
 use std::str;
-use std::rt::io::process::{Process, ProcessConfig, Ignored};
-use extra::arc::Arc;
-
-pub use ffi::{fuse_ino_t,Struct_fuse_entry_param};
+use std::local_data;
+use std::util;
+use std::rt::io::timer::Timer;
+use std::os;
+use std::run;
+use std::unstable::atomics::{AtomicBool, INIT_ATOMIC_BOOL, Acquire, Release};
+use extra::arc::{Arc, MutexArc, RWArc};
+use extra::enum_set::{EnumSet, CLike};
+use extra::time::Timespec;
+
+pub use ffi::fuse_ino_t;
+pub use buf::{Buf,MemBuf,FdBuf,BufVec};
+pub use errno::Errno;
+pub use name::FileName;
+pub use attr::{FileAttr,FileType,Permissions,Regular,Directory,Symlink,CharDevice,
+               BlockDevice,Fifo,Socket};
+pub use extra::time::Timespec;
+pub use ffi::{FUSE_IOCTL_COMPAT,FUSE_IOCTL_UNRESTRICTED,FUSE_IOCTL_RETRY,
+              FUSE_IOCTL_DIR};
 
 /// Information to be returned from open
 #[deriving(Default)]
 pub struct OpenReply {
     direct_io:bool,
     keep_cache:bool,
+    /// The file doesn't support seeking, like a pipe
+    nonseekable:bool,
     fh: u64
 }
 
+/// Whether a file was opened for reading, writing or both
+#[deriving(Eq, Clone)]
+pub enum AccessMode {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite
+}
+
+/// The flags passed to open(2), as given to `open`, `create` and `release`
+#[deriving(Eq, Clone)]
+pub struct OpenFlags {
+    priv bits: c_int
+}
+
+static O_ACCMODE: c_int = 3;
+
+impl OpenFlags {
+    pub fn from_bits(bits: c_int) -> OpenFlags {
+        OpenFlags{bits: bits}
+    }
+
+    pub fn bits(&self) -> c_int {
+        self.bits
+    }
+
+    /// How the file was opened, or None if the access mode bits are both set,
+    /// which isn't a valid mode
+    pub fn access_mode(&self) -> Option<AccessMode> {
+        match self.bits & O_ACCMODE {
+            O_RDONLY => Some(ReadOnly),
+            O_WRONLY => Some(WriteOnly),
+            O_RDWR => Some(ReadWrite),
+            _ => None
+        }
+    }
+
+    pub fn is_readable(&self) -> bool {
+        match self.access_mode() {
+            Some(ReadOnly) | Some(ReadWrite) => true,
+            _ => false
+        }
+    }
+
+    pub fn is_writable(&self) -> bool {
+        match self.access_mode() {
+            Some(WriteOnly) | Some(ReadWrite) => true,
+            _ => false
+        }
+    }
+
+    /// Writes go to the end of the file, wherever the offset says
+    pub fn append(&self) -> bool {
+        self.bits & O_APPEND != 0
+    }
+
+    /// The file is to be truncated to zero length.  Only passed to `open` if
+    /// the filesystem asks for `CapAtomicOTrunc`; otherwise the kernel calls
+    /// `setattr` to truncate.
+    pub fn truncate(&self) -> bool {
+        self.bits & O_TRUNC != 0
+    }
+
+    /// Only seen by `create`
+    pub fn create(&self) -> bool {
+        self.bits & O_CREAT != 0
+    }
+
+    /// `create` should fail with EEXIST if the file is already there
+    pub fn exclusive(&self) -> bool {
+        self.bits & O_EXCL != 0
+    }
+
+    /// The caller wants I/O to bypass caches
+    pub fn direct(&self) -> bool {
+        O_DIRECT != 0 && self.bits & O_DIRECT != 0
+    }
+
+    /// Writes should be on stable storage, data and metadata, before they
+    /// return
+    pub fn sync(&self) -> bool {
+        self.bits & O_SYNC == O_SYNC
+    }
+
+    /// Writes should be on stable storage, at least the data, before they
+    /// return.  True whenever `sync` is.
+    pub fn dsync(&self) -> bool {
+        // Linux's O_SYNC includes the O_DSYNC bit, but OS X's doesn't
+        self.bits & O_DSYNC != 0 || self.sync()
+    }
+
+    /// Reads shouldn't update the access time
+    pub fn noatime(&self) -> bool {
+        O_NOATIME != 0 && self.bits & O_NOATIME != 0
+    }
+
+    /// The bits that none of the methods above look at, such as O_NONBLOCK
+    pub fn unknown_bits(&self) -> c_int {
+        self.bits & !(O_ACCMODE | O_APPEND | O_TRUNC | O_CREAT | O_EXCL |
+                      O_DIRECT | O_SYNC | O_DSYNC | O_NOATIME)
+    }
+}
+
 pub struct CreateReply {
     open_reply: OpenReply,
     entry_param: EntryReply
 }
 
 pub struct AttrReply {
-    attr: libc::stat,
+    attr: FileAttr,
     attr_timeout: c_double
 }
 
+/**
+ * One attribute to change in `setattr`.  There's nothing here for setting
+ * ctime, or for the kernel's requests to clear the setuid and setgid bits
+ * (FATTR_CTIME, FATTR_KILL_SUIDGID): libfuse 2.9's `do_setattr` masks the
+ * valid bits down to the ones it knows about before calling us, so they never
+ * get this far.
+ */
 pub enum AttrToSet {
-    Mode(mode_t),
+    Mode(Permissions),
     Uid(uid_t),
     Gid(gid_t),
     Size(off_t),
-    Atime(time_t),
-    Mtime(time_t),
+    Atime(Timespec),
+    Mtime(Timespec),
 
     /// This is an instruction to set atime to the current time
     Atime_now,
@@ -70,16 +213,26 @@ pub enum AttrToSet {
 }
 
 pub enum ReadReply {
-    // TODO: support iov and fuse_bufvec type replies
-    // types of implementation
     DataBuffer(~[u8]),
+
+    /// Several buffers, sent one after another without first being joined
+    /// into one
+    Iov(~[~[u8]]),
+
+    /// The given number of bytes read from a file descriptor starting at the
+    /// given offset, and a combination of the `buf::FUSE_BUF_*` copy flags
+    /// saying whether to splice the data straight from the descriptor to the
+    /// kernel (and whether the pages may be moved rather than copied).  The
+    /// descriptor is left open.
+    FdRange(c_int, off_t, size_t, c_uint),
+
     EOF
 }
 
 pub struct DirEntry {
     ino: fuse_ino_t,
-    name: CString,
-    mode: mode_t,
+    name: FileName,
+    kind: FileType,
     next_offset: off_t
 }
 
@@ -87,150 +240,972 @@ pub enum ReaddirReply {
     DirEntries(~[DirEntry]),
 }
 
-pub type EntryReply = Struct_fuse_entry_param;
+/// A directory entry, as returned from `lookup` and the operations that create
+/// one.  The inode number is the one in `attr`.
+pub struct EntryReply {
+    /// Distinguishes this inode from others that had the same number before
+    generation: u64,
+    attr: FileAttr,
+    attr_timeout: c_double,
+    entry_timeout: c_double
+}
+
+/// One inode's worth of a batched forget: drop `nlookup` lookups of `ino`
+pub type ForgetData = Struct_fuse_forget_data;
+
+#[deriving(Eq, Clone)]
+pub enum LockKind {
+    ReadLock,
+    WriteLock,
+    Unlock
+}
+
+/// A POSIX (fcntl) record lock over the byte range `start` to `end`,
+/// inclusive.  A lock that runs to the end of the file, however far the file
+/// grows, has an `end` of `LOCK_TO_EOF`.
+pub struct FileLock {
+    start: off_t,
+    end: off_t,
+    kind: LockKind,
+    pid: pid_t
+}
+
+pub static LOCK_TO_EOF: off_t = 0x7fffffffffffffff;
+
+/// A region of the calling process's memory, identified by its address in
+/// that process
+pub struct IoctlIovec {
+    base: u64,
+    len: size_t
+}
+
+/// Information to be returned from ioctl.  Errors are returned as `Err`, the
+/// same as for any other operation.
+pub enum IoctlReply {
+    /// The ioctl's return value, and the data to copy back to the caller.
+    /// The data must fit in the `out_size` passed to `ioctl`; if it doesn't,
+    /// the caller gets EINVAL.
+    IoctlData(c_int, ~[u8]),
+
+    /// Ask the kernel to retry the ioctl after copying the first set of
+    /// regions in from the caller's memory, with room to copy the second set
+    /// back out.  Only allowed when the ioctl was called with
+    /// FUSE_IOCTL_UNRESTRICTED in its flags; otherwise the caller gets EINVAL.
+    IoctlRetry(~[IoctlIovec], ~[IoctlIovec])
+}
 
-/// The error result should be one of libc's errno values
-pub type ErrnoResult<T> = Result<T, c_int>;
+/// A set of poll() events, made up of the POLL* flags below
+pub type PollEvents = c_uint;
+pub static POLLIN: PollEvents = 0x001;
+pub static POLLPRI: PollEvents = 0x002;
+pub static POLLOUT: PollEvents = 0x004;
+pub static POLLERR: PollEvents = 0x008;
+pub static POLLHUP: PollEvents = 0x010;
+pub static POLLNVAL: PollEvents = 0x020;
+
+/**
+ * Handle passed to `poll` when the caller wants to be told about readiness
+ * changes.  The filesystem can keep it (and send it to other tasks), and call
+ * `notify` once the file becomes ready, which prompts the kernel to poll
+ * again.  Dropping the handle releases it back to FUSE.
+ */
+pub struct PollHandle {
+    priv handle: *mut Struct_fuse_pollhandle,
+    // The handle points into the session, so it's only usable while the
+    // session is alive
+    priv live: RWArc<LiveSession>
+}
+impl PollHandle {
+    /// Tell the kernel to poll again.  Fails with ENOTCONN if the filesystem
+    /// has been unmounted since the handle was made.
+    #[fixed_stack_segment]
+    pub fn notify(&self) -> ErrnoResult<()> {
+        do self.live.read |live| {
+            if live.chan.is_null() {
+                Err(ENOTCONN)
+            } else {
+                match unsafe { fuse_lowlevel_notify_poll(self.handle) } {
+                    0 => Ok(()),
+                    err => Err(Errno::from_raw(-err))
+                }
+            }
+        }
+    }
+}
+impl Drop for PollHandle {
+    #[fixed_stack_segment]
+    fn drop(&mut self) {
+        // Once the session is gone there's nothing safe to hand it back to
+        do self.live.read |live| {
+            if !live.chan.is_null() {
+                unsafe {
+                    fuse_pollhandle_destroy(self.handle);
+                }
+            }
+        }
+    }
+}
+
+/// A BSD flock() operation, which always covers the whole file
+#[deriving(Eq, Clone)]
+pub enum FlockOperation {
+    FlockShared,
+    FlockExclusive,
+    FlockUnlock
+}
+
+/// The error result is the errno to reply to the kernel with
+pub type ErrnoResult<T> = Result<T, Errno>;
+
+/// Optional features of the FUSE protocol, which a filesystem can turn on in
+/// `init` if the kernel is capable of them
+#[deriving(Eq, Clone)]
+pub enum Capability {
+    CapAsyncRead = 0,
+    CapPosixLocks = 1,
+    CapAtomicOTrunc = 3,
+    CapExportSupport = 4,
+    CapBigWrites = 5,
+    CapDontMask = 6,
+    CapSpliceWrite = 7,
+    CapSpliceMove = 8,
+    CapSpliceRead = 9,
+    CapFlockLocks = 10,
+    CapIoctlDir = 11
+}
+// Each capability's value is the number of its bit in the C API's flags
+impl CLike for Capability {
+    fn to_uint(&self) -> uint {
+        *self as uint
+    }
+    fn from_uint(v: uint) -> Capability {
+        match v {
+            0 => CapAsyncRead,
+            1 => CapPosixLocks,
+            3 => CapAtomicOTrunc,
+            4 => CapExportSupport,
+            5 => CapBigWrites,
+            6 => CapDontMask,
+            7 => CapSpliceWrite,
+            8 => CapSpliceMove,
+            9 => CapSpliceRead,
+            10 => CapFlockLocks,
+            11 => CapIoctlDir,
+            _ => fail!("No FUSE capability has bit %u", v)
+        }
+    }
+}
+static ALL_CAPABILITIES: [Capability, ..11] = [
+    CapAsyncRead, CapPosixLocks, CapAtomicOTrunc, CapExportSupport,
+    CapBigWrites, CapDontMask, CapSpliceWrite, CapSpliceMove, CapSpliceRead,
+    CapFlockLocks, CapIoctlDir
+];
+
+pub type Capabilities = EnumSet<Capability>;
+
+fn capabilities_from_bits(bits: c_uint) -> Capabilities {
+    let mut caps = EnumSet::empty();
+    for &cap in ALL_CAPABILITIES.iter() {
+        if bits & (1 << cap.to_uint()) as c_uint != 0 {
+            caps.add(cap);
+        }
+    }
+    caps
+}
+
+fn capabilities_to_bits(caps: Capabilities) -> c_uint {
+    caps.iter().fold(0 as c_uint, |bits, cap| bits | (1 << cap.to_uint()) as c_uint)
+}
+
+/**
+ * The parameters of the connection to the kernel, passed to `init`.  The
+ * protocol version and the kernel's capabilities are fixed; the rest can be
+ * changed to configure the connection.
+ */
+pub struct ConnectionConfig {
+    priv proto_major: uint,
+    priv proto_minor: uint,
+    priv capable: Capabilities,
+    /// Capabilities to turn on.  Any that the kernel isn't capable of are
+    /// ignored.  `CapPosixLocks` and `CapFlockLocks` start off, and should be
+    /// turned on by a filesystem that implements the lock operations.
+    want: Capabilities,
+    async_read: bool,
+    max_write: uint,
+    max_readahead: uint,
+    max_background: uint,
+    congestion_threshold: uint
+}
+impl ConnectionConfig {
+    pub fn proto_major(&self) -> uint { self.proto_major }
+    pub fn proto_minor(&self) -> uint { self.proto_minor }
+    /// The capabilities the kernel (and the FUSE library) support
+    pub fn capable(&self) -> Capabilities { self.capable }
+}
+
+/// Credentials of the process that made a request
+#[deriving(Clone)]
+pub struct RequestContext {
+    uid: uid_t,
+    gid: gid_t,
+    pid: pid_t,
+    umask: mode_t,
+    /// Supplementary groups.  Empty if the system doesn't make them available.
+    groups: ~[gid_t]
+}
+
+/**
+ * Get the credentials of the process that made the request that the calling
+ * task is handling, for doing permission checks or setting the owner of new
+ * files.  Returns None when called outside of a filesystem operation (such as
+ * in `init`, or in a task spawned by an operation.)  Finding the
+ * supplementary groups means reading from /proc, so it's best to call this at
+ * most once per operation.
+ */
+#[fixed_stack_segment]
+pub fn request_context() -> Option<RequestContext> {
+    do local_data::get(current_req) |req| {
+        match req {
+            Some(&req) => unsafe {
+                let ctx = fuse_req_ctx(req);
+                Some(RequestContext {
+                    uid: (*ctx).uid,
+                    gid: (*ctx).gid,
+                    pid: (*ctx).pid,
+                    umask: (*ctx).umask,
+                    groups: req_groups(req)
+                })
+            },
+            None => None
+        }
+    }
+}
+
+#[fixed_stack_segment]
+unsafe fn req_groups(req: fuse_req_t) -> ~[gid_t] {
+    let mut size = 32;
+    loop {
+        let mut groups = vec::from_elem(size as uint, 0 as gid_t);
+        let count = fuse_req_getgroups(req, size,
+                                       vec::raw::to_mut_ptr(groups));
+        if count < 0 {
+            return ~[];
+        } else if count <= size {
+            groups.truncate(count as uint);
+            return groups;
+        }
+        size = count;
+    }
+}
+
+// The request being handled by the current task, if any
+local_data_key!(current_req: fuse_req_t)
+
+struct CancellationState {
+    cancelled: bool,
+    callbacks: ~[~fn()]
+}
+
+/**
+ * Tells a filesystem operation whether the kernel has interrupted its request,
+ * which happens when the process that made it gets a signal (such as from
+ * Ctrl-C.)  An operation that finds its request interrupted should give up and
+ * return EINTR.  Get the token for the current operation from
+ * `cancellation_token`; it can be cloned and sent to other tasks.
+ */
+#[deriving(Clone)]
+pub struct CancellationToken {
+    priv state: MutexArc<CancellationState>
+}
+impl CancellationToken {
+    fn new() -> CancellationToken {
+        CancellationToken {
+            state: MutexArc::new(CancellationState {
+                    cancelled: false,
+                    callbacks: ~[]
+                })
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        unsafe { self.state.access(|st| st.cancelled) }
+    }
+
+    /// Arrange for `f` to be called when the request is interrupted, or right
+    /// away if it already has been.  `f` may be called on the FUSE C API
+    /// task, which handles all incoming requests, so it should do something
+    /// quick like sending on a chan.
+    pub fn on_cancel(&self, f: ~fn()) {
+        let f = Cell::new(f);
+        let cancelled = unsafe {
+            do self.state.access |st| {
+                if !st.cancelled {
+                    st.callbacks.push(f.take());
+                }
+                st.cancelled
+            }
+        };
+        if cancelled {
+            (f.take())();
+        }
+    }
+
+    fn cancel(&self) {
+        let callbacks = unsafe {
+            do self.state.access |st| {
+                st.cancelled = true;
+                util::replace(&mut st.callbacks, ~[])
+            }
+        };
+        for f in callbacks.move_iter() {
+            f();
+        }
+    }
+}
+
+/// The cancellation token for the request the calling task is handling, or
+/// None when called outside of a filesystem operation.
+pub fn cancellation_token() -> Option<CancellationToken> {
+    do local_data::get(current_token) |token| {
+        match token {
+            Some(t) => Some(t.clone()),
+            None => None
+        }
+    }
+}
+
+local_data_key!(current_token: CancellationToken)
+
+#[fixed_stack_segment]
+fn register_interrupt(req: fuse_req_t, token: &CancellationToken) {
+    unsafe {
+        fuse_req_interrupt_func(req, Some(interrupt_impl),
+                                ptr::to_unsafe_ptr(token) as *mut c_void);
+    }
+}
+
+// This has to happen before replying, because the reply frees the request.
+// Once it returns, FUSE won't touch the token passed to `register_interrupt`.
+#[fixed_stack_segment]
+fn unregister_interrupt(req: fuse_req_t) {
+    unsafe {
+        fuse_req_interrupt_func(req, None, ptr::mut_null());
+    }
+}
+
+extern fn interrupt_impl(_req: fuse_req_t, data: *mut c_void) {
+    unsafe {
+        (*(data as *CancellationToken)).cancel();
+    }
+}
+
+/// The filesystem operations, for telling which one failed.  Each is named
+/// after the `FuseLowLevelOps` method it stands for.
+#[deriving(Eq, Clone, ToStr)]
+pub enum Operation {
+    OpLookup = 0,
+    OpForget,
+    OpForgetMulti,
+    OpGetattr,
+    OpSetattr,
+    OpReadlink,
+    OpMknod,
+    OpMkdir,
+    OpUnlink,
+    OpRmdir,
+    OpSymlink,
+    OpRename,
+    OpLink,
+    OpOpen,
+    OpRead,
+    OpWrite,
+    OpWriteBuf,
+    OpFlush,
+    OpRelease,
+    OpFsync,
+    OpOpendir,
+    OpReaddir,
+    OpReleasedir,
+    OpFsyncdir,
+    OpStatfs,
+    OpSetxattr,
+    OpGetxattr,
+    OpListxattr,
+    OpRemovexattr,
+    OpAccess,
+    OpCreate,
+    OpGetlk,
+    OpSetlk,
+    OpFlock,
+    OpIoctl,
+    OpPoll,
+    OpBmap
+}
+impl CLike for Operation {
+    fn to_uint(&self) -> uint {
+        *self as uint
+    }
+    fn from_uint(v: uint) -> Operation {
+        if v < ALL_OPERATIONS.len() {
+            ALL_OPERATIONS[v]
+        } else {
+            fail!("No operation with the number %u", v)
+        }
+    }
+}
+static ALL_OPERATIONS: &'static [Operation] = &[
+    OpLookup,
+    OpForget,
+    OpForgetMulti,
+    OpGetattr,
+    OpSetattr,
+    OpReadlink,
+    OpMknod,
+    OpMkdir,
+    OpUnlink,
+    OpRmdir,
+    OpSymlink,
+    OpRename,
+    OpLink,
+    OpOpen,
+    OpRead,
+    OpWrite,
+    OpWriteBuf,
+    OpFlush,
+    OpRelease,
+    OpFsync,
+    OpOpendir,
+    OpReaddir,
+    OpReleasedir,
+    OpFsyncdir,
+    OpStatfs,
+    OpSetxattr,
+    OpGetxattr,
+    OpListxattr,
+    OpRemovexattr,
+    OpAccess,
+    OpCreate,
+    OpGetlk,
+    OpSetlk,
+    OpFlock,
+    OpIoctl,
+    OpPoll,
+    OpBmap
+];
 
 /**
  * Trait that defines the filesystem.  Override each method to implement the
  * corresponding filesystem operation.  See the FUSE docs for a description of
  * each.  The rust_fuse wrapper will run each operation in its own task, so
  * even though the API is blocking they will run in parallel (subject to rust's
- * default scheduling.)
+ * default scheduling.)  Within an operation, `request_context` gives the
+ * credentials of the process that made the request, and `cancellation_token`
+ * tells whether the request has been interrupted.
  *
- * For each operation implemented, it is necessary to also implement the
- * corresponding _is_implemented method and return true--i.e. if you implement
- * `lookup` you need to implement `lookup_is_implemented` and so on.  Each
- * "is_implemented" function is called only once, before the filesystem is
- * mounted, and if it returns false, the corresponding FS operation method will
- * not be called, and the FUSE default behavior will be used in its place.
- * It's a shame that all this is necessary, but rust has no reflection to allow
- * the wrapper to know which fields of the `fuse_lowlevel_ops` struct to pass
- * NULL into in the C API.  Passing NULL into the struct is the only way to get
- * the FUSE default behavior--there is no way to invoke it from a callback.
+ * Implementing a method is all it takes for the operation to be called.
+ * Every operation is passed to the C API, because rust has no reflection to
+ * tell which methods were overridden, so the default methods do what FUSE
+ * does for an operation the filesystem leaves out: most reply ENOSYS, while
+ * `open`, `opendir`, `release` and `releasedir` succeed, `statfs` describes
+ * an empty filesystem, and `write_buf` hands the data to `write`.  The lock
+ * operations are the exception, since the kernel decides at mount time
+ * whether to send them at all: they're only called if `init` turns on
+ * `CapPosixLocks` (for `getlk` and `setlk`) or `CapFlockLocks` (for `flock`).
  * 
  * The callbacks get an immutable reference to self, and can be called in
  * parallel on the same object.
  */
 pub trait FuseLowLevelOps {
-    /// Called when the file system is mounted and ready.
-    fn init(&self) { }
+    /// Called when the file system is mounted, before any other operation.
+    /// Change `conn` to configure the connection to the kernel.
+    fn init(&self, _conn: &mut ConnectionConfig) { }
     // Called when the file system has been unmounted.
     fn destroy(&self) { }
 
-    fn lookup(&self, _parent: fuse_ino_t, _name: &CString)
-              -> ErrnoResult<EntryReply> { fail!() }
-    fn lookup_is_implemented(&self) -> bool { false }
-    fn forget(&self, _ino:fuse_ino_t, _nlookup:c_ulong) { fail!() }
-    fn forget_is_implemented(&self) -> bool { false }
-    fn getattr(&self, _ino: fuse_ino_t) -> ErrnoResult<AttrReply> { fail!() }
-    fn getattr_is_implemented(&self) -> bool { false }
+    fn lookup(&self, _parent: fuse_ino_t, _name: &FileName)
+              -> ErrnoResult<EntryReply> { Err(ENOSYS) }
+    fn forget(&self, _ino:fuse_ino_t, _nlookup:c_ulong) { }
+    // Called with a batch of forgets.  The default calls `forget` for each,
+    // so a filesystem that only implements `forget` still gets the whole
+    // batch in one task.
+    fn forget_multi(&self, forgets: &[ForgetData]) {
+        for f in forgets.iter() {
+            self.forget(f.ino as fuse_ino_t, f.nlookup as c_ulong);
+        }
+    }
+    fn getattr(&self, _ino: fuse_ino_t) -> ErrnoResult<AttrReply> { Err(ENOSYS) }
     fn setattr(&self, _ino: fuse_ino_t, __attrs_toset:&[AttrToSet], _fh:Option<u64>)
-               -> ErrnoResult<AttrReply> { fail!() }
-    fn setattr_is_implemented(&self) -> bool { false }
-    fn readlink(&self, _ino: fuse_ino_t) -> ErrnoResult<~str> { fail!() }
-    fn readlink_is_implemented(&self) -> bool { false }
-    fn mknod(&self, _parent: fuse_ino_t, _name: &CString, _mode: mode_t, _rdev: dev_t) 
-             -> ErrnoResult<EntryReply> { fail!() }
-    fn mknod_is_implemented(&self) -> bool { false }
-    fn mkdir(&self, _parent: fuse_ino_t, _name: &CString, _mode: mode_t)
-             -> ErrnoResult<EntryReply> { fail!() }
-    fn mkdir_is_implemented(&self) -> bool { false }
-    fn unlink(&self, _parent: fuse_ino_t, _name: &CString)
-              -> ErrnoResult<()> { fail!() }
-    fn unlink_is_implemented(&self) -> bool { false }
-    fn rmdir(&self, _parent: fuse_ino_t, _name: &CString) -> ErrnoResult<()> { fail!() }
-    fn rmdir_is_implemented(&self) -> bool { false }
-    fn symlink(&self, _link:&CString, _parent: fuse_ino_t, _name: &CString)
-               -> ErrnoResult<EntryReply> { fail!() }
-    fn symlink_is_implemented(&self) -> bool { false }
-    fn rename(&self, _parent: fuse_ino_t, _name: &CString, _newparent: fuse_ino_t, 
-              _newname: &CString) -> ErrnoResult<()> { fail!() }
-    fn rename_is_implemented(&self) -> bool { false }
-    fn link(&self, _ino: fuse_ino_t, _newparent: fuse_ino_t, _newname: &CString)
-            -> ErrnoResult<EntryReply> { fail!() }
-    fn link_is_implemented(&self) -> bool { false }
-    fn open(&self, _ino: fuse_ino_t, _flags: c_int)
-            -> ErrnoResult<OpenReply> { fail!() }
-    fn open_is_implemented(&self) -> bool { false }
+               -> ErrnoResult<AttrReply> { Err(ENOSYS) }
+    fn readlink(&self, _ino: fuse_ino_t) -> ErrnoResult<FileName> { Err(ENOSYS) }
+    fn mknod(&self, _parent: fuse_ino_t, _name: &FileName, _kind: FileType,
+             _perm: Permissions, _rdev: dev_t) 
+             -> ErrnoResult<EntryReply> { Err(ENOSYS) }
+    fn mkdir(&self, _parent: fuse_ino_t, _name: &FileName, _perm: Permissions)
+             -> ErrnoResult<EntryReply> { Err(ENOSYS) }
+    fn unlink(&self, _parent: fuse_ino_t, _name: &FileName)
+              -> ErrnoResult<()> { Err(ENOSYS) }
+    fn rmdir(&self, _parent: fuse_ino_t, _name: &FileName) -> ErrnoResult<()> { Err(ENOSYS) }
+    fn symlink(&self, _link:&FileName, _parent: fuse_ino_t, _name: &FileName)
+               -> ErrnoResult<EntryReply> { Err(ENOSYS) }
+    fn rename(&self, _parent: fuse_ino_t, _name: &FileName, _newparent: fuse_ino_t, 
+              _newname: &FileName) -> ErrnoResult<()> { Err(ENOSYS) }
+    fn link(&self, _ino: fuse_ino_t, _newparent: fuse_ino_t, _newname: &FileName)
+            -> ErrnoResult<EntryReply> { Err(ENOSYS) }
+    fn open(&self, _ino: fuse_ino_t, _flags: OpenFlags)
+            -> ErrnoResult<OpenReply> {
+        Ok(OpenReply{direct_io: false, keep_cache: false, nonseekable: false,
+                     fh: 0})
+    }
     fn read(&self, _ino: fuse_ino_t, _size: size_t, _off: off_t, _fh: u64)
-            -> ErrnoResult<ReadReply> { fail!() }
-    fn read_is_implemented(&self) -> bool { false }
+            -> ErrnoResult<ReadReply> { Err(ENOSYS) }
     fn write(&self, _ino: fuse_ino_t, _buf:&[u8], _off: off_t, _fh: u64, _writepage: bool)
-             -> ErrnoResult<size_t> { fail!() }
-    fn write_is_implemented(&self) -> bool { false }
+             -> ErrnoResult<size_t> { Err(ENOSYS) }
+    // Like `write`, but the data may arrive in a pipe rather than in memory,
+    // and can be moved on to its destination with `BufVec::copy_to_fd`
+    // without being copied through userspace.  All writes come through here;
+    // the default reads the data into memory and passes it to `write`.
+    fn write_buf(&self, ino: fuse_ino_t, bufv: BufVec, off: off_t, fh: u64,
+                 writepage: bool) -> ErrnoResult<size_t> {
+        do bufv.into_bytes().and_then |data| {
+            self.write(ino, data, off, fh, writepage)
+        }
+    }
     fn flush(&self, _ino: fuse_ino_t, __lockowner: u64, _fh: u64)
-             -> ErrnoResult<()> { fail!() }
-    fn flush_is_implemented(&self) -> bool { false }
-    fn release(&self, _ino: fuse_ino_t, _flags: c_int, _fh: u64)
-               -> ErrnoResult<()> { fail!() }
-    fn release_is_implemented(&self) -> bool { false }
+             -> ErrnoResult<()> { Err(ENOSYS) }
+    // If `flock_release` is Some, any flock() locks held by that lock owner
+    // should be released along with the file.
+    // `flush` is set if the file should be flushed first, because the kernel
+    // didn't send a `flush` for the last close.
+    fn release(&self, _ino: fuse_ino_t, _flags: OpenFlags, _fh: u64,
+               _flush: bool, _flock_release: Option<u64>)
+               -> ErrnoResult<()> { Ok(()) }
     fn fsync(&self, _ino: fuse_ino_t, _datasync: bool, _fh: u64)
-             -> ErrnoResult<()> { fail!() }
-    fn fsync_is_implemented(&self) -> bool { false }
+             -> ErrnoResult<()> { Err(ENOSYS) }
     fn opendir(&self, _ino: fuse_ino_t)
-               -> ErrnoResult<OpenReply> { fail!() }
-    fn opendir_is_implemented(&self) -> bool { false }
+               -> ErrnoResult<OpenReply> {
+        Ok(OpenReply{direct_io: false, keep_cache: false, nonseekable: false,
+                     fh: 0})
+    }
     fn readdir(&self, _ino: fuse_ino_t, _size: size_t, _off: off_t, _fh: u64)
-               -> ErrnoResult<ReaddirReply> { fail!() }
-    fn readdir_is_implemented(&self) -> bool { false }
+               -> ErrnoResult<ReaddirReply> { Err(ENOSYS) }
     fn releasedir(&self, _ino: fuse_ino_t, _fh: u64)
-                  -> ErrnoResult<()> { fail!() }
-    fn releasedir_is_implemented(&self) -> bool { false }
+                  -> ErrnoResult<()> { Ok(()) }
     fn fsyncdir(&self, _ino: fuse_ino_t, _datasync: bool, _fh: u64)
-                -> ErrnoResult<()> { fail!() }
-    fn fsyncdir_is_implemented(&self) -> bool { false }
-    fn statfs(&self, _ino: fuse_ino_t) -> ErrnoResult<Struct_statvfs> { fail!() }
-    fn statfs_is_implemented(&self) -> bool { false }
-    fn setxattr(&self, _ino: fuse_ino_t, _name: &CString, _value: &[u8], _flags: c_int)
-                -> ErrnoResult<()> { fail!() }
-    fn setxattr_is_implemented(&self) -> bool { false }
+                -> ErrnoResult<()> { Err(ENOSYS) }
+    // The default is what FUSE itself replies: an empty filesystem with
+    // 512-byte blocks and names of up to 255 bytes
+    fn statfs(&self, _ino: fuse_ino_t) -> ErrnoResult<Struct_statvfs> {
+        Ok(Struct_statvfs{
+            f_bsize: 512, f_frsize: 0, f_blocks: 0, f_bfree: 0, f_bavail: 0,
+            f_files: 0, f_ffree: 0, f_favail: 0, f_fsid: 0, f_flag: 0,
+            f_namemax: 255, __f_spare: [0, ..6]
+        })
+    }
+    fn setxattr(&self, _ino: fuse_ino_t, _name: &FileName, _value: &[u8], _flags: c_int)
+                -> ErrnoResult<()> { Err(ENOSYS) }
     // _TODO: examine this--ReadReply may not be appropraite here
-    fn getxattr(&self, _ino: fuse_ino_t, _name: &CString, _size: size_t)
-                -> ErrnoResult<ReadReply> { fail!() }
+    fn getxattr(&self, _ino: fuse_ino_t, _name: &FileName, _size: size_t)
+                -> ErrnoResult<ReadReply> { Err(ENOSYS) }
     // Called on getxattr with size of zero (meaning a query of total size)
-    fn getxattr_size(&self, _ino: fuse_ino_t, _name: &CString)
-                     -> ErrnoResult<size_t>{ fail!() }
-    fn getxattr_is_implemented(&self) -> bool { false }
+    fn getxattr_size(&self, _ino: fuse_ino_t, _name: &FileName)
+                     -> ErrnoResult<size_t>{ Err(ENOSYS) }
     // _TODO: examine this--ReadReply may not be appropraite here
     fn listxattr(&self, _ino: fuse_ino_t, _size: size_t)
-                 -> ErrnoResult<ReadReply> { fail!() }
+                 -> ErrnoResult<ReadReply> { Err(ENOSYS) }
     // Called on listxattr with size of zero (meaning a query of total size)
-    fn listxattr_size(&self, _ino: fuse_ino_t) -> ErrnoResult<size_t> { fail!() }
-    fn listxattr_is_implemented(&self) -> bool { false }
-    fn removexattr(&self, _ino: fuse_ino_t, _name: &CString) 
-                   -> ErrnoResult<()> { fail!() }
-    fn removexattr_is_implemented(&self) -> bool { false }
-    fn access(&self, _ino: fuse_ino_t, _mask: c_int) -> ErrnoResult<()> { fail!() }
-    fn access_is_implemented(&self) -> bool { false }
-    fn create(&self, _parent: fuse_ino_t, _name: &CString, _mode: mode_t, _flags: c_int)
-              -> ErrnoResult<CreateReply> { fail!() }
-    fn create_is_implemented(&self) -> bool { false }
-    // _TODO: The following still need _implementing:
-    //
-    // getlk
-    // setlk
-    // bmap
-    // ioctl
-    // poll
-    // write_buf
-    // retrieve_reply
-    // forget_multi
-    // flock
-}
-
-
-/// Options for mounting the file system
-pub struct FuseMountOptions {
-    /// Command line arguments to pass through to the FUSE API.  See the
-    /// `fuse_ll_help` function in the FUSE source for what can go here.
-    args:~[~[u8]]
+    fn listxattr_size(&self, _ino: fuse_ino_t) -> ErrnoResult<size_t> { Err(ENOSYS) }
+    fn removexattr(&self, _ino: fuse_ino_t, _name: &FileName) 
+                   -> ErrnoResult<()> { Err(ENOSYS) }
+    fn access(&self, _ino: fuse_ino_t, _mask: c_int) -> ErrnoResult<()> { Err(ENOSYS) }
+    fn create(&self, _parent: fuse_ino_t, _name: &FileName, _perm: Permissions,
+              _flags: OpenFlags)
+              -> ErrnoResult<CreateReply> { Err(ENOSYS) }
+    // Return the first lock that would conflict with `lock`, or a copy of
+    // `lock` with a kind of `Unlock` if there is none.
+    fn getlk(&self, _ino: fuse_ino_t, _fh: u64, _lock_owner: u64,
+             _lock: &FileLock) -> ErrnoResult<FileLock> { Err(ENOSYS) }
+    // Acquire, change or release `lock`.  If `sleep` is true, wait for any
+    // conflicting lock to be released rather than returning EAGAIN.
+    fn setlk(&self, _ino: fuse_ino_t, _fh: u64, _lock_owner: u64,
+             _lock: &FileLock, _sleep: bool) -> ErrnoResult<()> { Err(ENOSYS) }
+    // Apply a BSD flock() operation to the whole file.  If `nonblocking` is
+    // false, wait for any conflicting lock to be released rather than
+    // returning EWOULDBLOCK.
+    fn flock(&self, _ino: fuse_ino_t, _fh: u64, _lock_owner: u64,
+             _op: FlockOperation, _nonblocking: bool)
+             -> ErrnoResult<()> { Err(ENOSYS) }
+    // `arg` is the raw argument the caller passed to ioctl, `in_buf` holds the
+    // data copied in from the caller, and `out_size` is the most data that
+    // can be returned.  `flags` is a combination of the FUSE_IOCTL_* flags.
+    fn ioctl(&self, _ino: fuse_ino_t, _cmd: c_int, _arg: u64, _in_buf: &[u8],
+             _out_size: size_t, _flags: c_uint, _fh: u64)
+             -> ErrnoResult<IoctlReply> { Err(ENOSYS) }
+    // Return the events that are currently ready.  `handle` is present if
+    // the caller wants to be notified of later changes.
+    fn poll(&self, _ino: fuse_ino_t, _fh: u64, _handle: Option<PollHandle>)
+            -> ErrnoResult<PollEvents> { Err(ENOSYS) }
+    // Map block `idx` of the file, in units of `blocksize`, to a block index
+    // on the underlying device.  Only useful for filesystems mounted with the
+    // blkdev option.
+    fn bmap(&self, _ino: fuse_ino_t, _blocksize: size_t, _idx: u64)
+            -> ErrnoResult<u64> { Err(ENOSYS) }
+}
+
+
+/// A filesystem operation whose task failed instead of replying
+pub struct OpFailure {
+    op: Operation,
+    /// The inode the operation was on.  For operations on a name in a
+    /// directory, such as `lookup` or `mkdir`, this is the directory.
+    ino: Option<fuse_ino_t>,
+    /// What the task failed with.  `task::try` only says that a task failed,
+    /// not why, so for now this is always None; the runtime still logs the
+    /// message when the task fails.
+    message: Option<~str>
+}
+
+/// What to do about a failed operation
+pub enum FailureAction {
+    /// Reply to the request with this errno
+    ReplyWithErrno(Errno),
+    /// Reply to the request with this errno, then unmount the filesystem
+    AbortMount(Errno)
+}
+
+/**
+ * Decides how to answer requests whose operation failed.  Give one to
+ * `FuseMountOptions::failure_policy`.  The policy is called from the failed
+ * operation's task, after the failure.  If the policy fails too, the request
+ * is answered with EIO.
+ */
+pub trait FailurePolicy {
+    fn on_failure(&self, failure: &OpFailure) -> FailureAction;
+}
+
+/// The policy used unless the mount options give another: log the failure
+/// and reply with EIO
+pub struct DefaultFailurePolicy;
+impl FailurePolicy for DefaultFailurePolicy {
+    fn on_failure(&self, failure: &OpFailure) -> FailureAction {
+        error!("%s failed (inode %?)", failure.op.to_str(), failure.ino);
+        ReplyWithErrno(EIO)
+    }
 }
-impl Default for FuseMountOptions {
-    fn default() -> FuseMountOptions {
+
+/**
+ * Options for mounting the file system
+ *
+ * Start with `FuseMountOptions::new` and chain the setters for whichever
+ * options are needed:
+ *
+ * ~~~
+ * let options = FuseMountOptions::new(&PosixPath("/mnt/hello"))
+ *     .fsname("hello").read_only(true).handle_signals(true);
+ * ~~~
+ */
+pub struct FuseMountOptions {
+    priv mount_point: PosixPath,
+    priv allow_other: bool,
+    priv allow_root: bool,
+    priv default_permissions: bool,
+    priv read_only: bool,
+    priv fsname: Option<~str>,
+    priv subtype: Option<~str>,
+    priv max_read: Option<uint>,
+    priv entry_timeout: Option<c_double>,
+    priv attr_timeout: Option<c_double>,
+    priv auto_unmount: bool,
+    priv debug: bool,
+    priv handle_signals: bool,
+    priv nonempty: bool,
+    priv raw_args: ~[~str],
+    priv failure_policy: ~FailurePolicy:Send+Freeze
+}
+impl FuseMountOptions {
+    /// Options for mounting at `mount_point`, with everything else left to
+    /// FUSE's defaults
+    pub fn new(mount_point: &PosixPath) -> FuseMountOptions {
         FuseMountOptions{
-            args: ~[]
+            mount_point: mount_point.clone(),
+            allow_other: false,
+            allow_root: false,
+            default_permissions: false,
+            read_only: false,
+            fsname: None,
+            subtype: None,
+            max_read: None,
+            entry_timeout: None,
+            attr_timeout: None,
+            auto_unmount: false,
+            debug: false,
+            handle_signals: false,
+            nonempty: false,
+            raw_args: ~[],
+            failure_policy: ~DefaultFailurePolicy as ~FailurePolicy:Send+Freeze
+        }
+    }
+
+    /// Let users other than the one mounting access the filesystem (the
+    /// `allow_other` option)
+    pub fn allow_other(self, allow: bool) -> FuseMountOptions {
+        FuseMountOptions{allow_other: allow, ..self}
+    }
+
+    /// Let root access the filesystem as well as the user mounting it (the
+    /// `allow_root` option)
+    pub fn allow_root(self, allow: bool) -> FuseMountOptions {
+        FuseMountOptions{allow_root: allow, ..self}
+    }
+
+    /// Have the kernel check permissions based on the file modes, rather than
+    /// leaving it to the filesystem (the `default_permissions` option)
+    pub fn default_permissions(self, check: bool) -> FuseMountOptions {
+        FuseMountOptions{default_permissions: check, ..self}
+    }
+
+    /// Mount read-only (the `ro` option)
+    pub fn read_only(self, ro: bool) -> FuseMountOptions {
+        FuseMountOptions{read_only: ro, ..self}
+    }
+
+    /// The name shown as the source of the mount, e.g. in /proc/mounts
+    pub fn fsname(self, name: &str) -> FuseMountOptions {
+        FuseMountOptions{fsname: Some(name.to_owned()), ..self}
+    }
+
+    /// The filesystem type shown for the mount, after "fuse."
+    pub fn subtype(self, name: &str) -> FuseMountOptions {
+        FuseMountOptions{subtype: Some(name.to_owned()), ..self}
+    }
+
+    /// The largest read request the kernel will send, in bytes
+    pub fn max_read(self, bytes: uint) -> FuseMountOptions {
+        FuseMountOptions{max_read: Some(bytes), ..self}
+    }
+
+    /// Cache names looked up for this many seconds, whatever timeout the
+    /// `lookup`, `mknod`, `mkdir`, `symlink`, `link` or `create` replies ask
+    /// for
+    pub fn entry_timeout(self, seconds: c_double) -> FuseMountOptions {
+        FuseMountOptions{entry_timeout: Some(seconds), ..self}
+    }
+
+    /// Cache attributes for this many seconds, whatever timeout the replies
+    /// that carry attributes ask for
+    pub fn attr_timeout(self, seconds: c_double) -> FuseMountOptions {
+        FuseMountOptions{attr_timeout: Some(seconds), ..self}
+    }
+
+    /// Unmount automatically if the process exits without unmounting (the
+    /// `auto_unmount` option, which needs a fusermount that supports it)
+    pub fn auto_unmount(self, auto: bool) -> FuseMountOptions {
+        FuseMountOptions{auto_unmount: auto, ..self}
+    }
+
+    /// Have FUSE print every request and reply to stderr
+    pub fn debug(self, debug: bool) -> FuseMountOptions {
+        FuseMountOptions{debug: debug, ..self}
+    }
+
+    /// Exit cleanly on SIGINT, SIGTERM or SIGHUP: stop the session, call
+    /// `destroy` and unmount, just as `FuseMount::unmount` does.  The
+    /// handlers are only installed for signals that have no handler yet, and
+    /// are removed when the filesystem is unmounted.  Only one mounted
+    /// filesystem at a time can handle signals; the others log a warning.
+    pub fn handle_signals(self, handle: bool) -> FuseMountOptions {
+        FuseMountOptions{handle_signals: handle, ..self}
+    }
+
+    /// Allow mounting over a directory that has files in it, which are hidden
+    /// until the filesystem is unmounted (the `nonempty` option)
+    pub fn nonempty(self, allow: bool) -> FuseMountOptions {
+        FuseMountOptions{nonempty: allow, ..self}
+    }
+
+    /// Decide how to answer requests whose operation fails, in place of
+    /// `DefaultFailurePolicy`
+    pub fn failure_policy(self, policy: ~FailurePolicy:Send+Freeze)
+                          -> FuseMountOptions {
+        FuseMountOptions{failure_policy: policy, ..self}
+    }
+
+    /// Pass an argument straight through to the FUSE API, for anything not
+    /// covered above.  See the `fuse_ll_help` and `fuse_mount_help` functions
+    /// in the FUSE source for what can go here.
+    pub fn raw_arg(self, arg: &str) -> FuseMountOptions {
+        let mut opts = self;
+        opts.raw_args.push(arg.to_owned());
+        opts
+    }
+
+    // Catch the usual problems with the mount point before handing it to
+    // FUSE, which would only say that the mount failed
+    #[fixed_stack_segment]
+    fn check_mount_point(&self) -> Result<(), MountError> {
+        let mut st = default_stat();
+        let stat_result = do self.mount_point.to_str().with_c_str |path| {
+            unsafe { libc::stat(path, ptr::to_mut_unsafe_ptr(&mut st)) }
+        };
+        if stat_result != 0 {
+            let errno = Errno::last();
+            return if errno == ENOENT || errno == ENOTDIR {
+                Err(MountPointMissing)
+            } else if errno == EACCES || errno == EPERM {
+                Err(PermissionDenied(errno))
+            } else {
+                Err(MountFailed(errno))
+            };
+        }
+        if st.st_mode as c_int & S_IFMT != S_IFDIR {
+            return Err(MountPointNotDirectory);
+        }
+        let nonempty_allowed = self.nonempty || self.raw_opt_given("nonempty");
+        if !nonempty_allowed && !os::list_dir(&self.mount_point).is_empty() {
+            return Err(MountPointNotEmpty);
+        }
+        check_fuse_device()
+    }
+
+    // Whether the raw arguments turn on the option `name`, as either
+    // "-o name,..." or "-oname,..."
+    fn raw_opt_given(&self, name: &str) -> bool {
+        let args = &self.raw_args;
+        let mut i = 0;
+        while i < args.len() {
+            let list = if args[i].as_slice() == "-o" && i + 1 < args.len() {
+                i += 1;
+                Some(args[i].as_slice())
+            } else if args[i].starts_with("-o") {
+                Some(args[i].slice_from(2))
+            } else {
+                None
+            };
+            for list in list.iter() {
+                if split_opts(*list).iter().any(|opt| opt.as_slice() == name) {
+                    return true;
+                }
+            }
+            i += 1;
+        }
+        false
+    }
+
+    // Render the options as the command line FUSE expects, including an
+    // argv[0]
+    fn to_args(&self) -> ~[~str] {
+        let mut opts = ~[];
+        if self.allow_other { opts.push(~"allow_other"); }
+        if self.allow_root { opts.push(~"allow_root"); }
+        if self.default_permissions { opts.push(~"default_permissions"); }
+        if self.read_only { opts.push(~"ro"); }
+        for name in self.fsname.iter() {
+            opts.push(format!("fsname={:s}", escape_opt(*name)));
+        }
+        for name in self.subtype.iter() {
+            opts.push(format!("subtype={:s}", escape_opt(*name)));
+        }
+        for bytes in self.max_read.iter() {
+            opts.push(format!("max_read={:u}", *bytes));
+        }
+        if self.auto_unmount { opts.push(~"auto_unmount"); }
+        if self.debug { opts.push(~"debug"); }
+        if self.nonempty { opts.push(~"nonempty"); }
+
+        let mut args = ~[~"rust_fuse"];
+        if !opts.is_empty() {
+            args.push(~"-o");
+            args.push(opts.connect(","));
+        }
+        args.push_all(self.raw_args);
+        args.push(self.mount_point.to_str());
+        args
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[fixed_stack_segment]
+fn check_fuse_device() -> Result<(), MountError> {
+    let access_result = do "/dev/fuse".with_c_str |dev| {
+        unsafe { libc::access(dev, R_OK | W_OK) }
+    };
+    if access_result != 0 {
+        Err(PermissionDenied(Errno::last()))
+    } else {
+        Ok(())
+    }
+}
+
+// OS X has a FUSE device per mount, which the mount helper finds for itself
+#[cfg(target_os = "macos")]
+fn check_fuse_device() -> Result<(), MountError> {
+    Ok(())
+}
+
+// FUSE splits -o options on commas, and takes a backslash as escaping the
+// character after it
+fn escape_opt(opt: &str) -> ~str {
+    opt.replace("\\", "\\\\").replace(",", "\\,")
+}
+
+// The reverse of escape_opt: split a -o list into its options
+fn split_opts(list: &str) -> ~[~str] {
+    let mut opts = ~[];
+    let mut current: ~[u8] = ~[];
+    let mut escaped = false;
+    for &b in list.as_bytes().iter() {
+        if escaped {
+            current.push(b);
+            escaped = false;
+        } else if b == '\\' as u8 {
+            escaped = true;
+        } else if b == ',' as u8 {
+            opts.push(str::from_utf8(current));
+            current = ~[];
+        } else {
+            current.push(b);
+        }
+    }
+    opts.push(str::from_utf8(current));
+    opts
+}
+
+/// Why a filesystem couldn't be mounted
+#[deriving(Eq, Clone)]
+pub enum MountError {
+    /// FUSE rejected the mount options
+    InvalidOptions,
+    /// The mount point doesn't exist
+    MountPointMissing,
+    /// The mount point isn't a directory
+    MountPointNotDirectory,
+    /// The mount point has files in it, and the `nonempty` option wasn't given
+    MountPointNotEmpty,
+    /// The mount point or the FUSE device can't be accessed, with the errno
+    PermissionDenied(Errno),
+    /// The mount point couldn't be looked at for some other reason, such as
+    /// ENOTCONN from a FUSE filesystem that's still mounted there but whose
+    /// process has gone
+    MountFailed(Errno),
+    /// FUSE couldn't mount the filesystem.  It prints the reason to stderr;
+    /// errno isn't reliable afterwards, since the mount may have been handed
+    /// to fusermount.
+    FuseMountFailed,
+    /// FUSE mounted the filesystem, but couldn't start a session on it
+    SessionCreationFailed
+}
+impl ToStr for MountError {
+    fn to_str(&self) -> ~str {
+        match *self {
+            InvalidOptions => ~"invalid mount options",
+            MountPointMissing => ~"mount point does not exist",
+            MountPointNotDirectory => ~"mount point is not a directory",
+            MountPointNotEmpty => ~"mount point is not empty",
+            PermissionDenied(errno) =>
+                format!("permission denied: {:s}", errno.to_str()),
+            MountFailed(errno) =>
+                format!("mount failed: {:s}", errno.to_str()),
+            FuseMountFailed => ~"FUSE failed to mount the filesystem",
+            SessionCreationFailed => ~"failed to create FUSE session"
         }
     }
 }
@@ -242,22 +1217,30 @@ impl Default for FuseMountOptions {
  * with the functions specified in `ops`.  The path will be mounted for as long
  * as this object is alive, or until the path is unmounted externally via
  * `fusermount -u` or `umount`.
+ *
+ * To wake its C API thread for unmounting, rust_fuse installs a handler that
+ * does nothing for SIGUSR1, and sends that signal to the thread.  The previous
+ * handler is put back once no filesystem is mounted, but until then the
+ * process shouldn't use SIGUSR1 for anything else.
  */
 pub struct FuseMount {
     // A message appearing here means we're done
     priv finish_port:Port<TaskResult>,
     priv mounted:bool,
     priv session:~FuseSession,
+    priv live: RWArc<LiveSession>,
+    // Failure counts, indexed by operation
+    priv failures: RWArc<~[uint]>,
     priv nocopies: NonCopyable
 }
 impl FuseMount {
 
-    /// Mount the FUSE file system using the functions in `ops`, with options
-    /// (including the mount point) taken from `options.args`.  This function
-    /// will fail if the options are not valid as per FUSE, or if FUSE fails to
-    /// mount.
+    /// Mount the FUSE file system using the functions in `ops`, at the mount
+    /// point and with the options given in `options`.  Returns an error if the
+    /// mount point isn't usable, if the options are not valid as per FUSE, or
+    /// if FUSE fails to mount.
     pub fn new(options:~FuseMountOptions,ops:~FuseLowLevelOps:Send+Freeze)
-               -> ~FuseMount {
+               -> Result<~FuseMount, MountError> {
         // The C API needs its own OS thread because it will block.  We want to
         // run all of the filesystem commands we get in parallel on their own
         // rust tasks, but we don't want to spawn a new OS thread for each of
@@ -268,34 +1251,59 @@ impl FuseMount {
         // receive commands and start a task (again on the default scheduler)
         // in which to run each one.
 
-        let FuseMountOptions{args:args} = *options;
+        match options.check_mount_point() {
+            Ok(()) => (),
+            Err(e) => return Err(e)
+        }
+
+        let args = options.to_args();
+        let handle_signals = options.handle_signals;
+        let entry_timeout = options.entry_timeout;
+        let attr_timeout = options.attr_timeout;
+        let mount_point_str = options.mount_point.to_str();
+        let FuseMountOptions{failure_policy: failure_policy, _} = *options;
+        let failure_policy = Arc::new(failure_policy);
+        let failures = RWArc::new(vec::from_elem(ALL_OPERATIONS.len(), 0u));
 
         let (dispatch_port, dispatch_chan) = stream::<~FSOperation>();
         let (finish_port, finish_chan) = stream::<TaskResult>();
         // This is how we get the session pointer out of the C API thread,
         // so that we can use it to end the session later.
         let (session_port, session_chan) = 
-            stream::<~FuseSession>();
+            stream::<Result<~FuseSession, MountError>>();
+        let live = RWArc::new(LiveSession{
+                chan: ptr::mut_null(),
+                session: ptr::mut_null(),
+                thread: 0 as pthread_t,
+                retrieves: ~[],
+                unmounted: false
+            });
 
         // Spawn the C API task
         let mut c_api_task = task();
         c_api_task.sched_mode(SingleThreaded);
         c_api_task.linked();
-        c_api_task.name(format!("FUSE C API - {:?}",
-                                args.map(|v| str::from_utf8(*v))));
+        c_api_task.name(format!("FUSE C API - {:s}", mount_point_str));
         c_api_task.opts.notify_chan = Some(finish_chan);
         let userdata = ~FuseUserData{
             args: args, 
+            handle_signals: handle_signals,
+            entry_timeout: entry_timeout,
+            attr_timeout: attr_timeout,
             ops:Arc::new(ops),
             dispatch_chan:dispatch_chan,
             session_chan: session_chan,
-            session: Cell::new_empty()
+            session: Cell::new_empty(),
+            live: live.clone()
         };
         c_api_task.spawn_with(userdata,c_api_loop);
         
-        // Receive the session.  The C API task will either send it or fail
-        // (and if it fails, we fail with it, thanks to linked failure.)
-        let session = session_port.recv();
+        // Receive the session, or the reason there isn't one.  (If the C API
+        // task fails outright, we fail with it, thanks to linked failure.)
+        let session = match session_port.recv() {
+            Ok(session) => session,
+            Err(e) => return Err(e)
+        };
 
         // Spawn the dispatch task
         let mut dispatch_task = task();
@@ -303,20 +1311,51 @@ impl FuseMount {
         dispatch_task.linked();
         dispatch_task.name(format!("FUSE dispatch - {:s}",
                                    session.mount_point.to_str()));
-        do dispatch_task.spawn_with(dispatch_port) |dispatch_port| {
+        let dispatch_state = (dispatch_port, failure_policy, failures.clone(),
+                              live.clone());
+        do dispatch_task.spawn_with(dispatch_state)
+            |(dispatch_port, failure_policy, failures, live)| {
             'dispatch: loop {
                 // try_recv won't deschedule if the port is closed, so
                 // we need to explicitly do this
                 task::deschedule();
                 match dispatch_port.try_recv() {
                     Some(fsop) => {
-                        do task().spawn_with(fsop) |fsop| {
+                        let op_state = (fsop, failure_policy.clone(),
+                                        failures.clone(), live.clone());
+                        do task().spawn_with(op_state)
+                            |(fsop, failure_policy, failures, live)| {
                             let req = fsop.req;
+                            let failure = OpFailure{
+                                op: fsop.op,
+                                ino: fsop.ino,
+                                message: None
+                            };
+                            // The token registered with FUSE has to outlive
+                            // the registration, which only ends just before
+                            // the reply is sent--possibly after the operation's
+                            // task has failed.
+                            let token = ~CancellationToken::new();
+                            register_interrupt(req, token);
+                            let op_token = Cell::new((*token).clone());
                             let result = do task::try {
+                                local_data::set(current_token, op_token.take());
                                 (fsop.operation_fn)(req)
                             };
                             if result.is_err() {
-                                reply_failure_err(req);
+                                do failures.write |counts| {
+                                    counts[failure.op.to_uint()] += 1;
+                                }
+                                let op = failure.op;
+                                match apply_failure_policy(failure_policy,
+                                                           failure) {
+                                    ReplyWithErrno(errno) =>
+                                        reply_failure_err(req, op, errno),
+                                    AbortMount(errno) => {
+                                        reply_failure_err(req, op, errno);
+                                        exit_session(&live);
+                                    }
+                                }
                             }
                         };
                     },
@@ -326,103 +1365,504 @@ impl FuseMount {
             debug!("done with dispatch");
         }
 
-        ~FuseMount{
+        Ok(~FuseMount{
             finish_port: finish_port,
             mounted: true,
             session:session,
+            live: live,
+            failures: failures,
             nocopies: NonCopyable::new()
+        })
+    }
+
+    /// Return true if the filesystem is still mounted, false if not. It could
+    /// be unmounted while the object is still alive if something external
+    /// unmounted it, or from a call to `unmount`.
+    pub fn is_mounted(&self) -> bool {
+        self.mounted && !self.finish_port.peek()
+    }
+
+    /// Block until the filesystem is unmounted
+    pub fn finish(&mut self) {
+        if self.mounted {
+            debug!("Waiting to finish mount of %s",
+                   self.mount_point().to_str());
+            self.finish_port.recv();
+            debug!("Mount finished: %s",
+                   self.mount_point().to_str());
+            self.mounted = false;
+        }
+    }
+
+    /// Unmount the file system, and wait for its session to end.  The unmount
+    /// is lazy: anything still using the filesystem gets ENOTCONN once the
+    /// session has ended.  Returns the errno if the kernel refused to
+    /// unmount, or EIO if the C API task failed rather than ending cleanly.
+    pub fn unmount(&mut self) -> ErrnoResult<()> {
+        if !self.mounted {
+            return Ok(());
+        }
+        self.mounted = false;
+        let mut unmounted: ErrnoResult<()> = Ok(());
+        if !self.finish_port.peek() {
+            debug!("Unmounting %s", self.mount_point().to_str());
+            exit_session(&self.live);
+            // Unless the session has already ended, take the kernel unmount
+            // over from the C API task, so that it only happens once
+            let take_over = do self.live.write |live| {
+                live.unmounted = !live.chan.is_null();
+                live.unmounted
+            };
+            if take_over {
+                unmounted = kernel_unmount(self.mount_point());
+            }
+            // The signal sent by exit_session is lost if it arrives just
+            // before the C API task goes back to reading from the kernel, so
+            // keep sending it until the task is done
+            let mut timer = Timer::new().unwrap();
+            while !self.finish_port.peek() {
+                timer.sleep(10);
+                exit_session(&self.live);
+            }
+        }
+        match self.finish_port.recv() {
+            task::Success => unmounted,
+            task::Failure => Err(EIO)
+        }
+    }
+
+    pub fn mount_point<'a>(&'a self) -> &'a PosixPath {
+        &self.session.mount_point
+    }
+
+    /// Get a handle for telling the kernel about changes to the filesystem
+    pub fn notifier(&self) -> Notifier {
+        Notifier{live: self.live.clone()}
+    }
+
+    /// How many times `op` has failed since the filesystem was mounted
+    pub fn failure_count(&self, op: Operation) -> uint {
+        do self.failures.read |counts| {
+            counts[op.to_uint()]
+        }
+    }
+}
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        debug!("Destroying mounter for %s", self.mount_point().to_str());
+        match self.unmount() {
+            Ok(()) => (),
+            Err(errno) => error!("Unmounting %s failed: %s",
+                                 self.mount_point().to_str(), errno.to_str())
+        }
+    }
+}
+
+/**
+ * Handle for telling the kernel about changes to the filesystem that didn't
+ * come through it, so it can drop what it has cached.  Get one from
+ * `FuseMount::notifier`, or from `notifier` inside a filesystem operation; it
+ * can be cloned and sent to other tasks.  Once the filesystem is unmounted,
+ * every method returns ENODEV.
+ *
+ * As in the C API, be careful calling these from inside a filesystem
+ * operation: the kernel may be holding locks that the notification needs.
+ */
+#[deriving(Clone)]
+pub struct Notifier {
+    priv live: RWArc<LiveSession>
+}
+impl Notifier {
+    /// Invalidate the cached attributes of `ino`, and its cached data from
+    /// `off` for `len` bytes.  A negative `off` invalidates only the
+    /// attributes, and a `len` of zero means to the end of the file.
+    #[fixed_stack_segment]
+    pub fn invalidate_inode(&self, ino: fuse_ino_t, off: off_t, len: off_t)
+                            -> ErrnoResult<()> {
+        do self.with_chan |chan| {
+            unsafe { fuse_lowlevel_notify_inval_inode(chan, ino, off, len) }
+        }
+    }
+
+    /// Invalidate the cached directory entry `name` in `parent`
+    #[fixed_stack_segment]
+    pub fn invalidate_entry(&self, parent: fuse_ino_t, name: &FileName)
+                            -> ErrnoResult<()> {
+        let namelen = name.len() as size_t;
+        do self.with_chan |chan| {
+            do name.with_c_str |name_ptr| {
+                unsafe {
+                    fuse_lowlevel_notify_inval_entry(chan, parent, name_ptr,
+                                                     namelen)
+                }
+            }
+        }
+    }
+
+    /// Tell the kernel that the entry `name` in `parent`, which refers to
+    /// `child`, has been deleted.  Unlike `invalidate_entry`, this also
+    /// removes the entry from any open directory listings.
+    #[fixed_stack_segment]
+    pub fn notify_delete(&self, parent: fuse_ino_t, child: fuse_ino_t,
+                         name: &FileName) -> ErrnoResult<()> {
+        let namelen = name.len() as size_t;
+        do self.with_chan |chan| {
+            do name.with_c_str |name_ptr| {
+                unsafe {
+                    fuse_lowlevel_notify_delete(chan, parent, child, name_ptr,
+                                                namelen)
+                }
+            }
+        }
+    }
+
+    /// Put `data` into the kernel's page cache for `ino`, starting at
+    /// `offset`, as though it had been read from the file.  Extends the file
+    /// if the data goes past its end.
+    #[fixed_stack_segment]
+    pub fn store(&self, ino: fuse_ino_t, offset: off_t, data: &[u8])
+                 -> ErrnoResult<()> {
+        let mut bufv = buf::c_bufvec_for_mem(data);
+        do self.with_chan |chan| {
+            unsafe {
+                fuse_lowlevel_notify_store(chan, ino, offset,
+                                           ptr::to_mut_unsafe_ptr(&mut bufv),
+                                           0)
+            }
+        }
+    }
+
+    /**
+     * Ask the kernel for up to `size` bytes of what it has in its page cache
+     * for `ino`, starting at `offset`.  The data arrives on the returned
+     * port; it may be shorter than asked for, or empty if none of it is
+     * cached.  If the filesystem is unmounted before the kernel answers,
+     * ENODEV arrives instead.
+     */
+    #[fixed_stack_segment]
+    pub fn retrieve(&self, ino: fuse_ino_t, offset: off_t, size: size_t)
+                    -> ErrnoResult<Port<ErrnoResult<~[u8]>>> {
+        let (port, chan) = stream::<ErrnoResult<~[u8]>>();
+        // Ownership of the chan passes to the C API, which hands it back to
+        // retrieve_reply_impl along with the data.  Until then it's listed in
+        // the live session, so that it still gets an answer if the session
+        // ends first.
+        let cookie: *mut c_void = unsafe { transmute(~chan) };
+        let result = do self.live.write |live| {
+            if live.chan.is_null() {
+                Err(ENODEV)
+            } else {
+                match unsafe {
+                    fuse_lowlevel_notify_retrieve(live.chan, ino, size, offset,
+                                                  cookie)
+                } {
+                    0 => {
+                        live.retrieves.push(cookie as uint);
+                        Ok(())
+                    },
+                    err => Err(Errno::from_raw(-err))
+                }
+            }
+        };
+        match result {
+            Ok(()) => Ok(port),
+            Err(errno) => {
+                let _chan: ~RetrieveChan = unsafe { transmute(cookie) };
+                Err(errno)
+            }
+        }
+    }
+
+    // The notify functions all return zero or a negated errno
+    fn with_chan(&self, f: &fn(*mut Struct_fuse_chan) -> c_int)
+                 -> ErrnoResult<()> {
+        do self.live.read |live| {
+            if live.chan.is_null() {
+                Err(ENODEV)
+            } else {
+                match f(live.chan) {
+                    0 => Ok(()),
+                    err => Err(Errno::from_raw(-err))
+                }
+            }
+        }
+    }
+}
+
+/// A notifier for the filesystem whose operation the calling task is
+/// handling, or None when called outside of a filesystem operation.
+pub fn notifier() -> Option<Notifier> {
+    do local_data::get(current_req) |req| {
+        match req {
+            Some(&req) => do get_fuse_userdata(req, ()) |userdata, _| {
+                Some(Notifier{live: userdata.live.clone()})
+            },
+            None => None
+        }
+    }
+}
+
+// The FUSE C API objects for a mount, shared between the C API task and
+// everything that needs to reach them from other tasks.  The C API task nulls
+// them out (under the write lock) before destroying them.
+struct LiveSession {
+    chan: *mut Struct_fuse_chan,
+    session: *mut Struct_fuse_session,
+    // The OS thread running the C API task
+    thread: pthread_t,
+    // Cookies of the retrieves the kernel hasn't answered yet.  Each is a
+    // ~RetrieveChan turned into a pointer.
+    retrieves: ~[uint],
+    // Set when FuseMount::unmount has unmounted from the kernel itself, so
+    // the C API task mustn't do it again
+    unmounted: bool
+}
+
+type RetrieveChan = Chan<ErrnoResult<~[u8]>>;
+
+// Take back the chan for a retrieve, unless it has already been answered
+fn take_retrieve(live: &mut LiveSession, cookie: *mut c_void)
+                 -> Option<~RetrieveChan> {
+    match live.retrieves.iter().position(|&c| c == cookie as uint) {
+        Some(i) => {
+            live.retrieves.swap_remove(i);
+            Some(unsafe { transmute(cookie) })
+        },
+        None => None
+    }
+}
+
+// The kernel won't answer retrieves once the session is over, so answer them
+// here, or whoever asked would wait forever
+fn fail_pending_retrieves(cookies: ~[uint]) {
+    for &cookie in cookies.iter() {
+        let chan: ~RetrieveChan = unsafe { transmute(cookie) };
+        chan.try_send(Err(ENODEV));
+    }
+}
+
+// Sent to the C API thread to interrupt its blocking read from the kernel
+static WAKE_SIGNAL: c_int = SIGUSR1;
+
+extern fn wake_signal_handler(_sig: c_int) {
+    // Nothing to do--interrupting the read is all that's needed
+}
+
+// The handler is process-wide, so it's installed by the first C API task to
+// need it, and the previous one is put back when the last of them is done.
+// Both counts are guarded by SIGNAL_LOCK, which guards all the process-wide
+// signal state here.
+static mut WAKE_HANDLER_USERS: uint = 0;
+static mut PREV_WAKE_HANDLER: uint = 0;
+static mut SIGNAL_LOCK: AtomicBool = INIT_ATOMIC_BOOL;
+
+fn with_signal_lock<T>(f: &fn() -> T) -> T {
+    unsafe {
+        while SIGNAL_LOCK.swap(true, Acquire) {
+            task::deschedule();
+        }
+        let result = f();
+        SIGNAL_LOCK.store(false, Release);
+        result
+    }
+}
+
+#[fixed_stack_segment]
+fn install_wake_handler() {
+    do with_signal_lock {
+        unsafe {
+            if WAKE_HANDLER_USERS == 0 {
+                let handler: extern "C" fn(c_int) = wake_signal_handler;
+                PREV_WAKE_HANDLER =
+                    signal(WAKE_SIGNAL, transmute(handler)) as uint;
+                // Without SA_RESTART, so that the signal interrupts the read
+                siginterrupt(WAKE_SIGNAL, 1);
+            }
+            WAKE_HANDLER_USERS += 1;
         }
     }
+}
 
-    /// Return true if the filesystem is still mounted, false if not. It could
-    /// be unmounted while the object is still alive if something external
-    /// unmounted it, or from a call to `unmount`.
-    pub fn is_mounted(&self) -> bool {
-        self.mounted && !self.finish_port.peek()
+#[fixed_stack_segment]
+fn remove_wake_handler() {
+    do with_signal_lock {
+        unsafe {
+            WAKE_HANDLER_USERS -= 1;
+            if WAKE_HANDLER_USERS == 0 {
+                signal(WAKE_SIGNAL, PREV_WAKE_HANDLER as *c_void);
+            }
+        }
     }
+}
 
-    /// Block until the filesystem is unmounted
-    pub fn finish(&mut self) {
-        if self.mounted {
-            debug!("Waiting to finish mount of %s",
-                   self.mount_point().to_str());
-            self.finish_port.recv();
-            debug!("Mount finished: %s",
-                   self.mount_point().to_str());
-            self.mounted = false;
-        }
+// The handlers for `handle_signals` can run on any thread, and can't safely
+// do more there than write to a pipe.  A task of its own blocks reading the
+// other end, and ends the session for them.  Only one mount at a time can own
+// the pipe.  The handlers are only installed for signals that have none yet,
+// as FUSE's own are; EXIT_HANDLERS_SET records which ones were.
+static EXIT_SIGNALS: [c_int, ..3] = [libc::SIGHUP, libc::SIGINT,
+                                      libc::SIGTERM];
+static mut EXIT_PIPE_WRITE: c_int = -1;
+static mut EXIT_HANDLERS_SET: [bool, ..3] = [false, false, false];
+
+#[fixed_stack_segment]
+extern fn exit_signal_handler(sig: c_int) {
+    let byte = sig as u8;
+    unsafe {
+        libc::write(EXIT_PIPE_WRITE, ptr::to_unsafe_ptr(&byte) as *c_void, 1);
     }
+}
 
-    /// Unmount the file system
-    #[fixed_stack_segment]
-    pub fn unmount(&mut self) {
-        if self.mounted {
-            debug!("Unmounting %s", self.mount_point().to_str());
-            // TODO: once signal handling exists, signal the C API thread
-            // instead of using an external process.
-            unmount_via_external_process(self.mount_point());
-            self.finish();
+// Start ending `live`'s session on any of EXIT_SIGNALS.  Returns false if
+// another mount already handles them, or there's no pipe to be had.
+#[fixed_stack_segment]
+fn watch_for_exit_signals(live: RWArc<LiveSession>) -> bool {
+    let read_fd = do with_signal_lock {
+        unsafe {
+            let mut fds = [-1 as c_int, -1];
+            if EXIT_PIPE_WRITE != -1 ||
+                libc::pipe(vec::raw::to_mut_ptr(fds)) == -1 {
+                None
+            } else {
+                EXIT_PIPE_WRITE = fds[1];
+                let handler: extern "C" fn(c_int) = exit_signal_handler;
+                for (i, &sig) in EXIT_SIGNALS.iter().enumerate() {
+                    let prev = signal(sig, transmute(handler));
+                    EXIT_HANDLERS_SET[i] = prev.is_null();
+                    if !prev.is_null() {
+                        signal(sig, prev);
+                    }
+                }
+                Some(fds[0])
+            }
+        }
+    };
+    match read_fd {
+        None => false,
+        Some(read_fd) => {
+            // Reading blocks, so the watcher gets a thread to itself
+            let mut watcher = task();
+            watcher.sched_mode(SingleThreaded);
+            watcher.unlinked();
+            do watcher.spawn_with(live) |live| {
+                // This ends when stop_watching_for_exit_signals closes the
+                // write end
+                while read_exit_signal(read_fd) {
+                    exit_session(&live);
+                }
+                unsafe { libc::close(read_fd); }
+            }
+            true
         }
     }
+}
 
-    pub fn mount_point<'a>(&'a self) -> &'a PosixPath {
-        &self.session.mount_point
+// Wait for an exit signal.  Returns false once the pipe has been closed.
+#[fixed_stack_segment]
+fn read_exit_signal(fd: c_int) -> bool {
+    let mut byte = 0u8;
+    loop {
+        let buf = ptr::to_mut_unsafe_ptr(&mut byte) as *mut c_void;
+        match unsafe { libc::read(fd, buf, 1) } {
+            1 => return true,
+            -1 if Errno::last() == EINTR => (),
+            _ => return false
+        }
     }
 }
-impl Drop for FuseMount {
-    fn drop(&mut self) {
-        debug!("Destroying mounter for %s", self.mount_point().to_str());
-        self.unmount();
+
+#[fixed_stack_segment]
+fn stop_watching_for_exit_signals() {
+    do with_signal_lock {
+        unsafe {
+            for (i, &sig) in EXIT_SIGNALS.iter().enumerate() {
+                if EXIT_HANDLERS_SET[i] {
+                    signal(sig, ptr::null());
+                    EXIT_HANDLERS_SET[i] = false;
+                }
+            }
+            libc::close(EXIT_PIPE_WRITE);
+            EXIT_PIPE_WRITE = -1;
+        }
     }
 }
 
+// Detach the filesystem at `path` from the kernel, which ends the session's
+// connection once nothing is using it.  A process that isn't allowed to
+// unmount goes through fusermount, as the C API does.
 #[cfg(target_os = "linux")]
-mod ext_unmount {
-    use std::path::PosixPath;
-    pub static EXT_UNMOUNT_PROG:&'static str = "fusermount";
-    pub fn ext_unmount_args(mount_point:&PosixPath) -> ~[~str] {
-        ~[~"-u", mount_point.to_str()]
+#[fixed_stack_segment]
+fn kernel_unmount(path: &PosixPath) -> ErrnoResult<()> {
+    let path = path.to_str();
+    let result = do path.with_c_str |c_path| {
+        match unsafe { umount2(c_path, MNT_DETACH) } {
+            0 => Ok(()),
+            _ => Err(Errno::last())
+        }
+    };
+    match result {
+        Err(errno) if errno == EPERM => {
+            let args = [~"-u", ~"-q", ~"-z", ~"--", path];
+            match run::process_status("fusermount", args) {
+                0 => Ok(()),
+                _ => Err(errno)
+            }
+        }
+        result => result
     }
 }
+
 #[cfg(target_os = "macos")]
-mod ext_unmount {
-    use std::path::PosixPath;
-    pub static EXT_UNMOUNT_PROG:&'static str = "umount";
-    pub fn ext_unmount_args(mount_point:&PosixPath) -> ~[~str] {
-        ~[mount_point.to_str()]
-    }
-}
-
-fn unmount_via_external_process(mount_point:&PosixPath) {
-    let args = self::ext_unmount::ext_unmount_args(mount_point);
-    let io = ~[Ignored, Ignored, Ignored];
-    let cwd = Some("/");
-    let _proc = Process::new(ProcessConfig{
-        program: self::ext_unmount::EXT_UNMOUNT_PROG,
-        args: args,
-        env: None,
-        cwd: cwd,
-        io: io
-    });
+#[fixed_stack_segment]
+fn kernel_unmount(path: &PosixPath) -> ErrnoResult<()> {
+    do path.to_str().with_c_str |c_path| {
+        match unsafe { unmount(c_path, MNT_FORCE) } {
+            0 => Ok(()),
+            _ => Err(Errno::last())
+        }
+    }
+}
+
+// Make the C API task leave its session loop.  It spends most of its time
+// blocked reading from the kernel, so besides marking the session as exited,
+// interrupt the read with WAKE_SIGNAL.
+#[fixed_stack_segment]
+fn exit_session(live: &RWArc<LiveSession>) {
+    do live.read |live| {
+        if !live.session.is_null() {
+            unsafe {
+                fuse_session_exit(live.session);
+                pthread_kill(live.thread, WAKE_SIGNAL);
+            }
+        }
+    }
 }
 
 // The FUSE userdata pointer will point to one of these.  The c extern fns
 // use it to get back into the correct corresponding rust tasks.
 struct FuseUserData {
     ops: Arc<~FuseLowLevelOps:Send+Freeze>,
-    args: ~[~[u8]],
+    args: ~[~str],
+    handle_signals: bool,
+    // Timeouts that override the ones in the filesystem's replies
+    entry_timeout: Option<c_double>,
+    attr_timeout: Option<c_double>,
     // Send FS command functions through here to be dispatched to new tasks on
     // the right scheduler
     dispatch_chan:Chan<~FSOperation>,
     // During initialization, we need to send the session through the session
     // chan
     session:Cell<~FuseSession>,
-    session_chan:Chan<~FuseSession>
+    session_chan:Chan<Result<~FuseSession, MountError>>,
+    // The C API channel, for sending notifications
+    live:RWArc<LiveSession>
 }
 
 struct FSOperation {
     operation_fn: ~fn(fuse_req_t),
-    req: fuse_req_t
+    req: fuse_req_t,
+    op: Operation,
+    ino: Option<fuse_ino_t>
 }
 
 struct FuseSession {
@@ -435,109 +1875,182 @@ fn cstr_as_bytes_no_term<'a>(cs:&'a CString) -> &'a[u8] {
     ab.slice_to(cmp::max(ab.len()-1,0))
 }
 
+// Names in requests point into the C API thread's buffer, which is reused for
+// the next request, so they're copied before the operation is dispatched.
+unsafe fn name_from_c(name: *c_schar) -> FileName {
+    let cs = CString::new(name, false);
+    // The kernel never sends a name with a NUL in it, since that's where C
+    // thinks the name ends
+    FileName::new(cstr_as_bytes_no_term(&cs)).unwrap()
+}
+
 #[fixed_stack_segment]
 pub fn c_api_loop(userdata:~FuseUserData) {
     unsafe {
-        let args = &(userdata.args);
-        let args_c_strs = args.map(|vec| vec.to_c_str());
-        let args_ptrs = args_c_strs.map(|cstr| cstr.with_ref(|ptr| ptr));
         let mut fuse_args = Struct_fuse_args {
-            argv: transmute(vec::raw::to_ptr(args_ptrs)),
-            argc: args.len() as c_int,
+            argv: ptr::mut_null(),
+            argc: 0,
             allocated: 0
         };
+        // Nothing here may fail the task: FuseMount::new is waiting for the
+        // session, or a MountError saying why there isn't one
+        for arg in userdata.args.iter() {
+            let added = !arg.contains_char('\0') && do arg.with_c_str |c_arg| {
+                fuse_opt_add_arg(ptr::to_mut_unsafe_ptr(&mut fuse_args),
+                                 c_arg) != -1
+            };
+            if !added {
+                error!("Failed to add FUSE argument %s", *arg);
+                fuse_opt_free_args(ptr::to_mut_unsafe_ptr(&mut fuse_args));
+                userdata.session_chan.send(Err(InvalidOptions));
+                return;
+            }
+        }
+
+        do userdata.live.write |live| {
+            live.thread = pthread_self();
+        }
 
         let mut mount_point:*mut c_schar = ptr::mut_null();
         if fuse_parse_cmdline(ptr::to_mut_unsafe_ptr(&mut fuse_args),
                               ptr::to_mut_unsafe_ptr(&mut mount_point),
                               ptr::mut_null(), // multithreaded--we ignore
                               ptr::mut_null() // foreground--ignore (for now)
-                              ) == -1 {
-            fail!("Invalid command line options");
+                              ) == -1 || mount_point.is_null() {
+            fuse_opt_free_args(ptr::to_mut_unsafe_ptr(&mut fuse_args));
+            userdata.session_chan.send(Err(InvalidOptions));
+            return;
         }
+        let mountpoint_cstr = CString::new(mount_point as *c_schar,false);
+        let mountpoint_str = match str::from_utf8_opt(
+            cstr_as_bytes_no_term(&mountpoint_cstr)) {
+            Some(s) => s,
+            None => {
+                fuse_opt_free_args(ptr::to_mut_unsafe_ptr(&mut fuse_args));
+                userdata.session_chan.send(Err(InvalidOptions));
+                return;
+            }
+        };
 
         // The fuse_chan here is a FUSE C API object, not to be confused
         // with a rust stream's "chan"
         let fuse_chan = fuse_mount(mount_point as *c_schar,
                                    ptr::to_mut_unsafe_ptr(&mut fuse_args));
         if fuse_chan == ptr::mut_null() {
-            fail!("Failed to mount");
+            fuse_opt_free_args(ptr::to_mut_unsafe_ptr(&mut fuse_args));
+            userdata.session_chan.send(Err(FuseMountFailed));
+            return;
+        }
+        do userdata.live.write |live| {
+            live.chan = fuse_chan;
         }
 
-        let llo = make_fuse_ll_oper(*userdata.ops.get());
+        let llo = make_fuse_ll_oper();
         let fuse_session = fuse_lowlevel_new(
             ptr::to_mut_unsafe_ptr(&mut fuse_args),
             ptr::to_unsafe_ptr(&llo),
             size_of::<Struct_fuse_lowlevel_ops>() as size_t,
             ptr::to_unsafe_ptr(&userdata) as *mut c_void);
         if fuse_session == ptr::mut_null() {
-            fail!("Failed to create FUSE session");
+            do userdata.live.write |live| {
+                live.chan = ptr::mut_null();
+            }
+            fuse_unmount(mount_point as *c_schar, fuse_chan);
+            fuse_opt_free_args(ptr::to_mut_unsafe_ptr(&mut fuse_args));
+            userdata.session_chan.send(Err(SessionCreationFailed));
+            return;
+        }
+        do userdata.live.write |live| {
+            live.session = fuse_session;
         }
-        let mountpoint_cstr = CString::new(mount_point as *c_schar,false);
-        let mountpoint_str = str::from_utf8(
-            cstr_as_bytes_no_term(&mountpoint_cstr));
         userdata.session.put_back(~FuseSession{
                 session:fuse_session,
                 mount_point:PosixPath(mountpoint_str)
             });
 
+        install_wake_handler();
+
+        let handling_signals = userdata.handle_signals &&
+            watch_for_exit_signals(userdata.live.clone());
+        if userdata.handle_signals && !handling_signals {
+            warn!("Failed to set signal handlers for %s", mountpoint_str);
+        }
+
         fuse_session_add_chan(fuse_session, fuse_chan);
         fuse_session_loop(fuse_session);
         debug!("Done with C API fuse session");
+        let (pending_retrieves, unmounted) = do userdata.live.write |live| {
+            live.chan = ptr::mut_null();
+            live.session = ptr::mut_null();
+            (util::replace(&mut live.retrieves, ~[]), live.unmounted)
+        };
+        fail_pending_retrieves(pending_retrieves);
+        // Nothing signals this thread once the session is cleared
+        remove_wake_handler();
         fuse_session_remove_chan(fuse_chan);
 
+        if handling_signals {
+            stop_watching_for_exit_signals();
+        }
         fuse_session_destroy(fuse_session);
-        fuse_unmount(mount_point as *c_schar, fuse_chan);
+        if unmounted {
+            fuse_chan_destroy(fuse_chan);
+        } else {
+            fuse_unmount(mount_point as *c_schar, fuse_chan);
+        }
+        fuse_opt_free_args(ptr::to_mut_unsafe_ptr(&mut fuse_args));
         debug!("Done with C API fn");
     };
 }
 
-pub fn make_fuse_ll_oper(ops:&FuseLowLevelOps)
-                         -> Struct_fuse_lowlevel_ops {
+// Every operation is passed on, since the default methods stand in for FUSE's
+// own handling (see FuseLowLevelOps)
+pub fn make_fuse_ll_oper() -> Struct_fuse_lowlevel_ops {
     return Struct_fuse_lowlevel_ops {
         init: Some(init_impl),
         destroy: Some(destroy_impl),
 
-        lookup: if ops.lookup_is_implemented() { Some(lookup_impl) } else { None },
-        forget: if ops.forget_is_implemented() { Some(forget_impl) } else { None },
-        getattr: if ops.getattr_is_implemented() { Some(getattr_impl) } else { None },
-        setattr: if ops.setattr_is_implemented() { Some(setattr_impl) } else { None },
-        readlink: if ops.readlink_is_implemented() { Some(readlink_impl) } else { None },
-        mknod: if ops.mknod_is_implemented() { Some(mknod_impl) } else { None },
-        mkdir: if ops.mkdir_is_implemented() { Some(mkdir_impl) } else { None },
-        unlink: if ops.unlink_is_implemented() { Some(unlink_impl) } else { None },
-        rmdir: if ops.rmdir_is_implemented() { Some(rmdir_impl) } else { None },
-        symlink: if ops.symlink_is_implemented() { Some(symlink_impl) } else { None },
-        rename: if ops.rename_is_implemented() { Some(rename_impl) } else { None },
-        link: if ops.link_is_implemented() { Some(link_impl) } else { None },
-        open: if ops.open_is_implemented() { Some(open_impl) } else { None },
-        read: if ops.read_is_implemented() { Some(read_impl) } else { None },
-        write: if ops.write_is_implemented() { Some(write_impl) } else { None },
-        flush: if ops.flush_is_implemented() { Some(flush_impl) } else { None },
-        release: if ops.release_is_implemented() { Some(release_impl) } else { None },
-        fsync: if ops.fsync_is_implemented() { Some(fsync_impl) } else { None },
-        opendir: if ops.opendir_is_implemented() { Some(opendir_impl) } else { None },
-        readdir: if ops.readdir_is_implemented() { Some(readdir_impl) } else { None },
-        releasedir: if ops.releasedir_is_implemented() { Some(releasedir_impl) } else { None },
-        fsyncdir: if ops.fsyncdir_is_implemented() { Some(fsyncdir_impl) } else { None },
-        statfs: if ops.statfs_is_implemented() { Some(statfs_impl) } else { None },
-        setxattr: if ops.setxattr_is_implemented() { Some(setxattr_impl) } else { None },
-        getxattr: if ops.getxattr_is_implemented() { Some(getxattr_impl) } else { None },
-        listxattr: if ops.listxattr_is_implemented() { Some(listxattr_impl) } else { None },
-        removexattr: if ops.removexattr_is_implemented() { Some(removexattr_impl) } else { None },
-        access: if ops.access_is_implemented() { Some(access_impl) } else { None },
-        create: if ops.create_is_implemented() { Some(create_impl) } else { None },
-
-        // Not implemented yet:
-        getlk: None,
-        setlk: None,
-        bmap: None,
-        ioctl: None,
-        poll: None,
-        write_buf: None,
-        retrieve_reply: None,
-        forget_multi: None,
-        flock: None,
+        lookup: Some(lookup_impl),
+        forget: Some(forget_impl),
+        forget_multi: Some(forget_multi_impl),
+        getattr: Some(getattr_impl),
+        setattr: Some(setattr_impl),
+        readlink: Some(readlink_impl),
+        mknod: Some(mknod_impl),
+        mkdir: Some(mkdir_impl),
+        unlink: Some(unlink_impl),
+        rmdir: Some(rmdir_impl),
+        symlink: Some(symlink_impl),
+        rename: Some(rename_impl),
+        link: Some(link_impl),
+        open: Some(open_impl),
+        read: Some(read_impl),
+        write: Some(write_impl),
+        write_buf: Some(write_buf_impl),
+        flush: Some(flush_impl),
+        release: Some(release_impl),
+        fsync: Some(fsync_impl),
+        opendir: Some(opendir_impl),
+        readdir: Some(readdir_impl),
+        releasedir: Some(releasedir_impl),
+        fsyncdir: Some(fsyncdir_impl),
+        statfs: Some(statfs_impl),
+        setxattr: Some(setxattr_impl),
+        getxattr: Some(getxattr_impl),
+        listxattr: Some(listxattr_impl),
+        removexattr: Some(removexattr_impl),
+        access: Some(access_impl),
+        create: Some(create_impl),
+        getlk: Some(getlk_impl),
+        setlk: Some(setlk_impl),
+        flock: Some(flock_impl),
+        ioctl: Some(ioctl_impl),
+        poll: Some(poll_impl),
+        bmap: Some(bmap_impl),
+
+        // This one isn't a filesystem operation; it delivers the data
+        // requested by `Notifier::retrieve`
+        retrieve_reply: Some(retrieve_reply_impl),
     }
 }
 
@@ -572,34 +2085,49 @@ type ReplySuccessFn<T> = ~fn(req:fuse_req_t, reply:T);
 #[fixed_stack_segment]
 fn send_fuse_reply<T>(result:ErrnoResult<T>, req:fuse_req_t,
                       reply_success:ReplySuccessFn<T>) {
+    unregister_interrupt(req);
     match result {
         Ok(reply) => reply_success(req, reply),
-        Err(errno) => unsafe { fuse_reply_err(req, errno); },
+        Err(errno) => {
+            let errno = if errno.is_valid() {
+                errno
+            } else {
+                error!("Replying with EIO in place of invalid errno %d",
+                       errno.to_raw() as int);
+                EIO
+            };
+            unsafe { fuse_reply_err(req, errno.to_raw()); }
+        }
     };
 }
 
-fn send_to_dispatch<T:Send>(req:fuse_req_t, arg:T, 
-                       blk:~fn(&FuseUserData, T)) {
+fn send_to_dispatch<T:Send>(req:fuse_req_t, op: Operation,
+                            ino: Option<fuse_ino_t>, arg:T,
+                            blk:~fn(&FuseUserData, T)) {
     // Toss use this to pass ownedship of arg and blk deep into the nested
     // closures...
     do get_fuse_userdata(req, (arg, blk)) |userdata, (arg, blk)| {
         let c = Cell::new((arg, blk));
         userdata.dispatch_chan.send(~FSOperation{
             operation_fn: |req| {
+                    local_data::set(current_req, req);
                     do get_fuse_userdata(req, ()) 
                         |userdata, ()| {
                         let (arg, blk) = c.take();
                         blk(userdata, arg);
                     }
                 },
-                req: req
+                req: req,
+                op: op,
+                ino: ino
             });
     }
 }
 
-fn run_for_reply<T>(req:fuse_req_t, reply_success:ReplySuccessFn<T>,
+fn run_for_reply<T>(req:fuse_req_t, op: Operation, ino: Option<fuse_ino_t>,
+                    reply_success:ReplySuccessFn<T>,
                     do_op:~fn(&FuseLowLevelOps) -> ErrnoResult<T>) {
-    do send_to_dispatch(req, (do_op, reply_success))
+    do send_to_dispatch(req, op, ino, (do_op, reply_success))
         |userdata, (do_op, reply_success)| {
         send_fuse_reply(do_op(*userdata.ops.get()), req, reply_success);
     }
@@ -607,16 +2135,40 @@ fn run_for_reply<T>(req:fuse_req_t, reply_success:ReplySuccessFn<T>,
 
 #[fixed_stack_segment]
 fn reply_entryparam(req: fuse_req_t, reply:EntryReply) {
+    let entry_param = entry_reply_to_c(override_entry_timeouts(req, reply));
     unsafe {
-        fuse_reply_entry(req, ptr::to_unsafe_ptr(&reply));
+        fuse_reply_entry(req, ptr::to_unsafe_ptr(&entry_param));
     }
 }
 
 #[fixed_stack_segment]
 fn reply_attr(req: fuse_req_t, reply: AttrReply) {
+    let attr_timeout = do get_fuse_userdata(req, ()) |userdata, ()| {
+        userdata.attr_timeout.unwrap_or(reply.attr_timeout)
+    };
+    let st = reply.attr.to_stat();
     unsafe {
-        fuse_reply_attr(req, ptr::to_unsafe_ptr(&reply.attr),
-                        reply.attr_timeout);
+        fuse_reply_attr(req, ptr::to_unsafe_ptr(&st), attr_timeout);
+    }
+}
+
+fn entry_reply_to_c(reply: EntryReply) -> Struct_fuse_entry_param {
+    Struct_fuse_entry_param{
+        ino: reply.attr.ino,
+        generation: reply.generation as c_ulong,
+        attr: reply.attr.to_stat(),
+        attr_timeout: reply.attr_timeout,
+        entry_timeout: reply.entry_timeout
+    }
+}
+
+// Apply the timeouts from the mount options, if any were given
+fn override_entry_timeouts(req: fuse_req_t, reply: EntryReply) -> EntryReply {
+    do get_fuse_userdata(req, reply) |userdata, reply| {
+        let mut reply = reply;
+        for t in userdata.entry_timeout.iter() { reply.entry_timeout = *t; }
+        for t in userdata.attr_timeout.iter() { reply.attr_timeout = *t; }
+        reply
     }
 }
 
@@ -628,7 +2180,7 @@ fn reply_none(req: fuse_req_t, _arg:()) {
 }
 
 #[fixed_stack_segment]
-fn reply_readlink(req: fuse_req_t, link:~str) {
+fn reply_readlink(req: fuse_req_t, link:FileName) {
     do link.with_c_str() |c_link| {
         unsafe {
             fuse_reply_readlink(req, c_link);
@@ -644,10 +2196,37 @@ fn reply_zero_err(req: fuse_req_t, _arg:()) {
 }
 
 #[fixed_stack_segment]
-fn reply_failure_err(req:fuse_req_t)
-{
+fn reply_einval(req: fuse_req_t) {
     unsafe {
-        fuse_reply_err(req, EIO);
+        fuse_reply_err(req, EINVAL.to_raw());
+    }
+}
+
+// Ask the failure policy what to do.  If the policy fails too, the request
+// still has to be answered, so fall back to EIO.
+fn apply_failure_policy(policy: Arc<~FailurePolicy:Send+Freeze>,
+                        failure: OpFailure) -> FailureAction {
+    let op = failure.op;
+    let action = do task::try {
+        policy.get().on_failure(&failure)
+    };
+    match action {
+        Ok(action) => action,
+        Err(()) => {
+            error!("The failure policy failed while handling %s",
+                   op.to_str());
+            ReplyWithErrno(EIO)
+        }
+    }
+}
+
+// Answer a request whose operation failed, with the errno the failure policy
+// chose.  Forgets never get an error reply, only the empty one.
+fn reply_failure_err(req:fuse_req_t, op: Operation, errno: Errno) {
+    if op == OpForget || op == OpForgetMulti {
+        send_fuse_reply(Ok(()), req, reply_none);
+    } else {
+        send_fuse_reply(Err(errno), req, reply_zero_err);
     }
 }
 
@@ -655,6 +2234,7 @@ fn openreply_to_fileinfo(reply: OpenReply) -> Struct_fuse_file_info {
     Struct_fuse_file_info{
         direct_io: to_bit(reply.direct_io) as c_uint,
         keep_cache: to_bit(reply.keep_cache) as c_uint,
+        nonseekable: to_bit(reply.nonseekable) as c_uint,
         fh: reply.fh,
         ..Default::default()
     }
@@ -672,7 +2252,9 @@ fn reply_open(req: fuse_req_t, reply: OpenReply) {
 fn reply_create(req: fuse_req_t, reply: CreateReply) {
     unsafe {
         let fi = openreply_to_fileinfo(reply.open_reply);
-        fuse_reply_create(req, ptr::to_unsafe_ptr(&(reply.entry_param)),
+        let entry_param = entry_reply_to_c(
+            override_entry_timeouts(req, reply.entry_param));
+        fuse_reply_create(req, ptr::to_unsafe_ptr(&entry_param),
                           ptr::to_unsafe_ptr(&fi));
     }
 }
@@ -685,6 +2267,18 @@ fn reply_read(req: fuse_req_t, reply: ReadReply) {
                 fuse_reply_buf(req, vec::raw::to_ptr(vec) as *c_schar,
                                vec.len() as size_t);
             },
+            Iov(bufs) => {
+                let iovs = bufs.map(|b| Struct_iovec {
+                        iov_base: vec::raw::to_ptr(*b) as *c_void,
+                        iov_len: b.len() as size_t
+                    });
+                fuse_reply_iov(req, vec::raw::to_ptr(iovs),
+                               iovs.len() as c_int);
+            },
+            FdRange(fd, offset, len, flags) => {
+                let mut bufv = buf::c_bufvec_for(&FdBuf(fd, Some(offset), len));
+                fuse_reply_data(req, ptr::to_mut_unsafe_ptr(&mut bufv), flags);
+            },
             EOF => {
                 fuse_reply_buf(req, ptr::null(), 0);
             }
@@ -701,7 +2295,7 @@ fn reply_readdir(req: fuse_req_t, tuple: (size_t, ReaddirReply)) {
             // of the length of the name, but this should be enough for
             // what's needed
             static EXTRA_CAP_PER_ENTRY:size_t = 32;
-            let mut lengths = entries.iter().map(|x| x.name.as_bytes().len()
+            let mut lengths = entries.iter().map(|x| x.name.len()
                                                  as size_t);
             let max_buf_size = lengths.sum() +
                 (entries.len() as size_t*EXTRA_CAP_PER_ENTRY);
@@ -714,9 +2308,9 @@ fn reply_readdir(req: fuse_req_t, tuple: (size_t, ReaddirReply)) {
                     let buf_ptr = ptr::mut_offset(vec::raw::to_mut_ptr(buf),
                                                   returned_size as int);
                     let remaining_size = buf_size - returned_size;
-                    let added_size = do entry.name.with_ref |name_cstr| {
+                    let added_size = do entry.name.with_c_str |name_cstr| {
                         let stbuf = libc::stat{
-                            st_mode: entry.mode,
+                            st_mode: entry.kind.to_mode(),
                             st_ino: entry.ino,
                             ..default_stat()
                         };
@@ -761,10 +2355,137 @@ fn reply_xattr(req: fuse_req_t, size: size_t) {
     }
 }
 
-extern fn init_impl(userdata:*mut c_void, _conn:*Struct_fuse_conn_info) {
+#[fixed_stack_segment]
+fn reply_lock(req: fuse_req_t, lock: FileLock) {
+    unsafe {
+        let fl = file_lock_to_flock(&lock);
+        fuse_reply_lock(req, ptr::to_unsafe_ptr(&fl));
+    }
+}
+
+#[fixed_stack_segment]
+fn reply_poll(req: fuse_req_t, revents: PollEvents) {
+    unsafe {
+        fuse_reply_poll(req, revents);
+    }
+}
+
+#[fixed_stack_segment]
+fn reply_bmap(req: fuse_req_t, idx: u64) {
+    unsafe {
+        fuse_reply_bmap(req, idx);
+    }
+}
+
+fn ioctl_iovecs_to_c(iovs: &[IoctlIovec]) -> ~[Struct_iovec] {
+    iovs.map(|iov| Struct_iovec {
+        iov_base: iov.base as uint as *c_void,
+        iov_len: iov.len
+    })
+}
+
+#[fixed_stack_segment]
+fn reply_ioctl(req: fuse_req_t, tuple: (size_t, c_uint, IoctlReply)) {
+    let (out_size, flags, reply) = tuple;
+    unsafe {
+        match reply {
+            // The kernel fails the whole ioctl with EIO if there's more data
+            // than the caller made room for, so say why instead
+            IoctlData(_, ref data) if data.len() as size_t > out_size => {
+                error!("ioctl replied with %u bytes, but only %u fit",
+                       data.len(), out_size as uint);
+                reply_einval(req);
+            },
+            IoctlData(result, data) => {
+                fuse_reply_ioctl(req, result,
+                                 vec::raw::to_ptr(data) as *c_void,
+                                 data.len() as size_t);
+            },
+            // Likewise, the kernel only allows a retry for unrestricted ioctls
+            IoctlRetry(*) if flags & FUSE_IOCTL_UNRESTRICTED == 0 => {
+                error!("ioctl asked for a retry, but it isn't unrestricted");
+                reply_einval(req);
+            },
+            IoctlRetry(in_iovs, out_iovs) => {
+                let in_c = ioctl_iovecs_to_c(in_iovs);
+                let out_c = ioctl_iovecs_to_c(out_iovs);
+                fuse_reply_ioctl_retry(req,
+                                       vec::raw::to_ptr(in_c),
+                                       in_c.len() as size_t,
+                                       vec::raw::to_ptr(out_c),
+                                       out_c.len() as size_t);
+            }
+        }
+    }
+}
+
+// FUSE passes locks around in the form fcntl uses, where a length of zero
+// means "to the end of the file".
+fn flock_to_file_lock(fl: &Struct_flock) -> FileLock {
+    FileLock {
+        start: fl.l_start,
+        end: if fl.l_len == 0 { LOCK_TO_EOF } else { fl.l_start + fl.l_len - 1 },
+        kind: match fl.l_type {
+            F_RDLCK => ReadLock,
+            F_WRLCK => WriteLock,
+            _ => Unlock
+        },
+        pid: fl.l_pid
+    }
+}
+
+fn file_lock_to_flock(lock: &FileLock) -> Struct_flock {
+    Struct_flock {
+        l_type: match lock.kind {
+            ReadLock => F_RDLCK,
+            WriteLock => F_WRLCK,
+            Unlock => F_UNLCK
+        },
+        l_whence: SEEK_SET as c_short,
+        l_start: lock.start,
+        l_len: if lock.end == LOCK_TO_EOF { 0 } else { lock.end - lock.start + 1 },
+        l_pid: lock.pid
+    }
+}
+
+extern fn init_impl(userdata:*mut c_void, conn:*mut Struct_fuse_conn_info) {
     do userdata_from_ptr(userdata, ()) |userdata, _| {
-        (*userdata.ops.get()).init();
-        userdata.session_chan.send(userdata.session.take());
+        unsafe {
+            // The C API asks for the lock capabilities because the lock
+            // operations are always passed to it, but they're only wanted if
+            // the filesystem implements them, which it says by turning them
+            // back on
+            let mut lock_caps = EnumSet::empty();
+            lock_caps.add(CapPosixLocks);
+            lock_caps.add(CapFlockLocks);
+            let mut config = ConnectionConfig {
+                proto_major: (*conn).proto_major as uint,
+                proto_minor: (*conn).proto_minor as uint,
+                capable: capabilities_from_bits((*conn).capable),
+                want: capabilities_from_bits((*conn).want) - lock_caps,
+                async_read: (*conn).async_read != 0,
+                max_write: (*conn).max_write as uint,
+                max_readahead: (*conn).max_readahead as uint,
+                max_background: (*conn).max_background as uint,
+                congestion_threshold: (*conn).congestion_threshold as uint
+            };
+            (*userdata.ops.get()).init(&mut config);
+            if !config.capable.contains(config.want) {
+                warn!("Ignoring FUSE capabilities the kernel lacks: %?",
+                      config.want - config.capable);
+            }
+            // Leave alone any bits the C API set that there's no Capability
+            // for, so they aren't turned off behind its back
+            let known_bits = capabilities_to_bits(capabilities_from_bits(!0));
+            (*conn).want = ((*conn).want & !known_bits) | capabilities_to_bits(
+                config.want.intersection(config.capable));
+            (*conn).async_read = to_bit(config.async_read) as c_uint;
+            (*conn).max_write = config.max_write as c_uint;
+            (*conn).max_readahead = config.max_readahead as c_uint;
+            (*conn).max_background = config.max_background as c_uint;
+            (*conn).congestion_threshold = config.congestion_threshold as c_uint;
+        }
+        userdata.session_chan.send(Ok(userdata.session.take()));
     }
 }
 
@@ -775,20 +2496,33 @@ extern fn destroy_impl(userdata:*mut c_void) {
 }
 
 extern fn lookup_impl(req:fuse_req_t,  parent:fuse_ino_t, name:*c_schar) {
-    do run_for_reply(req, reply_entryparam) |ops| {
-        unsafe { ops.lookup(parent, &CString::new(name, false)) }
+    let name = unsafe { name_from_c(name) };
+    do run_for_reply(req, OpLookup, Some(parent), reply_entryparam) |ops| {
+        ops.lookup(parent, &name)
     }
 }
 
 extern fn forget_impl(req: fuse_req_t, ino: fuse_ino_t, nlookup:c_ulong) {
-    do run_for_reply(req, reply_none) |ops| {
+    do run_for_reply(req, OpForget, Some(ino), reply_none) |ops| {
         ops.forget(ino, nlookup); Ok(())            
     }
 }
 
+extern fn forget_multi_impl(req: fuse_req_t, count: size_t,
+                            forgets: *mut Struct_fuse_forget_data) {
+    // The array belongs to the C API, so copy it before dispatching
+    let forgets = unsafe {
+        vec::raw::from_buf_raw(forgets as *Struct_fuse_forget_data,
+                               count as uint)
+    };
+    do run_for_reply(req, OpForgetMulti, None, reply_none) |ops| {
+        ops.forget_multi(forgets); Ok(())
+    }
+}
+
 extern fn getattr_impl(req:fuse_req_t, ino: fuse_ino_t,
                        _fi:*Struct_fuse_file_info) {
-    do run_for_reply(req, reply_attr) |ops| {
+    do run_for_reply(req, OpGetattr, Some(ino), reply_attr) |ops| {
         ops.getattr(ino)
     }
 }
@@ -803,250 +2537,500 @@ extern fn setattr_impl(req: fuse_req_t, ino: fuse_ino_t, attr:*libc::stat,
     static FUSE_SET_ATTR_MTIME:int = (1 << 5);
     static FUSE_SET_ATTR_ATIME_NOW:int = (1 << 7);
     static FUSE_SET_ATTR_MTIME_NOW:int = (1 << 8);
-    do run_for_reply(req, reply_attr) |ops| {
-        unsafe {
-            let mut attrs_to_set:~[AttrToSet] = vec::with_capacity(8);
-            if to_set & FUSE_SET_ATTR_MODE != 0 {
-                attrs_to_set.push(Mode((*attr).st_mode))
-            }
-            if to_set & FUSE_SET_ATTR_UID != 0 {
-                attrs_to_set.push(Uid((*attr).st_uid))
-            }
-            if to_set & FUSE_SET_ATTR_GID != 0 {
-                attrs_to_set.push(Gid((*attr).st_gid))
-            }
-            if to_set & FUSE_SET_ATTR_SIZE != 0 {
-                attrs_to_set.push(Size((*attr).st_size))
-            }
-            if to_set & FUSE_SET_ATTR_ATIME != 0 {
-                attrs_to_set.push(Atime((*attr).st_atime))
-            }
-            if to_set & FUSE_SET_ATTR_MTIME != 0 {
-                attrs_to_set.push(Mtime((*attr).st_mtime))
-            }
-            if to_set & FUSE_SET_ATTR_ATIME_NOW != 0 {
-                attrs_to_set.push(Atime_now)
-            }
-            if to_set & FUSE_SET_ATTR_MTIME_NOW != 0 {
-                attrs_to_set.push(Mtime_now)
-            }
-            ops.setattr(ino, attrs_to_set, fi.to_option().map(|fi| fi.fh))
+    // The attributes and file info belong to the C API, so copy out what's
+    // needed before dispatching
+    let (attrs_to_set, fh) = unsafe {
+        let mut attrs_to_set:~[AttrToSet] = vec::with_capacity(8);
+        if to_set & FUSE_SET_ATTR_MODE != 0 {
+            attrs_to_set.push(Mode(Permissions::from_mode((*attr).st_mode)))
         }
-        
+        if to_set & FUSE_SET_ATTR_UID != 0 {
+            attrs_to_set.push(Uid((*attr).st_uid))
+        }
+        if to_set & FUSE_SET_ATTR_GID != 0 {
+            attrs_to_set.push(Gid((*attr).st_gid))
+        }
+        if to_set & FUSE_SET_ATTR_SIZE != 0 {
+            attrs_to_set.push(Size((*attr).st_size))
+        }
+        if to_set & FUSE_SET_ATTR_ATIME != 0 {
+            attrs_to_set.push(Atime(Timespec::new(
+                (*attr).st_atime as i64, (*attr).st_atime_nsec as i32)))
+        }
+        if to_set & FUSE_SET_ATTR_MTIME != 0 {
+            attrs_to_set.push(Mtime(Timespec::new(
+                (*attr).st_mtime as i64, (*attr).st_mtime_nsec as i32)))
+        }
+        if to_set & FUSE_SET_ATTR_ATIME_NOW != 0 {
+            attrs_to_set.push(Atime_now)
+        }
+        if to_set & FUSE_SET_ATTR_MTIME_NOW != 0 {
+            attrs_to_set.push(Mtime_now)
+        }
+        (attrs_to_set, fi.to_option().map(|fi| fi.fh))
+    };
+    do run_for_reply(req, OpSetattr, Some(ino), reply_attr) |ops| {
+        ops.setattr(ino, attrs_to_set, fh)
     }
 }
 
 extern fn readlink_impl(req: fuse_req_t, ino: fuse_ino_t) {
-    do run_for_reply(req, reply_readlink) |ops| {
+    do run_for_reply(req, OpReadlink, Some(ino), reply_readlink) |ops| {
         ops.readlink(ino)
     }
 }
 
 extern fn mknod_impl(req:fuse_req_t, parent: fuse_ino_t, name:*c_schar,
                      mode: mode_t, rdev: dev_t) {
-    do run_for_reply(req, reply_entryparam) |ops| {
-        unsafe { ops.mknod(parent, &CString::new(name,false), mode, rdev) }
+    let kind = match FileType::from_mode(mode) {
+        Some(kind) => kind,
+        None => {
+            reply_einval(req);
+            return;
+        }
+    };
+    let perm = Permissions::from_mode(mode);
+    let name = unsafe { name_from_c(name) };
+    do run_for_reply(req, OpMknod, Some(parent), reply_entryparam) |ops| {
+        ops.mknod(parent, &name, kind, perm, rdev)
     }
 }
 
 extern fn mkdir_impl(req: fuse_req_t, parent: fuse_ino_t, name:*c_schar,
                      mode:mode_t) {
-    do run_for_reply(req, reply_entryparam) |ops| {
-        unsafe { ops.mkdir(parent, &CString::new(name,false), mode) }        
+    let name = unsafe { name_from_c(name) };
+    do run_for_reply(req, OpMkdir, Some(parent), reply_entryparam) |ops| {
+        ops.mkdir(parent, &name, Permissions::from_mode(mode))
     }
 }
 
 extern fn unlink_impl(req: fuse_req_t, parent: fuse_ino_t, name:*c_schar) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe { ops.unlink(parent, &CString::new(name,false)) }        
+    let name = unsafe { name_from_c(name) };
+    do run_for_reply(req, OpUnlink, Some(parent), reply_zero_err) |ops| {
+        ops.unlink(parent, &name)
     }
 }
 
 extern fn rmdir_impl(req: fuse_req_t, parent: fuse_ino_t, name:*c_schar) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe { ops.rmdir(parent, &CString::new(name,false)) }
+    let name = unsafe { name_from_c(name) };
+    do run_for_reply(req, OpRmdir, Some(parent), reply_zero_err) |ops| {
+        ops.rmdir(parent, &name)
     }
 }
 
 extern fn symlink_impl(req: fuse_req_t, link: *c_schar, parent: fuse_ino_t,
                        name: *c_schar) {
-    do run_for_reply(req, reply_entryparam) |ops| {
-        unsafe {
-            ops.symlink(&CString::new(link,false), parent, 
-                        &CString::new(name,false))
-        }        
+    let (link, name) = unsafe { (name_from_c(link), name_from_c(name)) };
+    do run_for_reply(req, OpSymlink, Some(parent), reply_entryparam) |ops| {
+        ops.symlink(&link, parent, &name)
     }
 }
 
 extern fn rename_impl(req: fuse_req_t, parent: fuse_ino_t, name: *c_schar,
                       newparent: fuse_ino_t, newname: *c_schar) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe {
-            ops.rename(parent, &CString::new(name,false), newparent,
-                       &CString::new(newname,false))
-        }        
+    let (name, newname) = unsafe {
+        (name_from_c(name), name_from_c(newname))
+    };
+    do run_for_reply(req, OpRename, Some(parent), reply_zero_err) |ops| {
+        ops.rename(parent, &name, newparent, &newname)
     }
 }
 
 extern fn link_impl(req: fuse_req_t, ino: fuse_ino_t, newparent: fuse_ino_t,
                     newname: *c_schar) {
-    do run_for_reply(req, reply_entryparam) |ops| {
-        unsafe {
-            ops.link(ino, newparent, &CString::new(newname,false))
-        }        
+    let newname = unsafe { name_from_c(newname) };
+    do run_for_reply(req, OpLink, Some(ino), reply_entryparam) |ops| {
+        ops.link(ino, newparent, &newname)
     }
 }
 
 extern fn open_impl(req: fuse_req_t, ino: fuse_ino_t,
                     fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_open) |ops| {
-        unsafe {
-            ops.open(ino, (*fi).flags)
-        }        
+    let flags = unsafe { OpenFlags::from_bits((*fi).flags) };
+    do run_for_reply(req, OpOpen, Some(ino), reply_open) |ops| {
+        ops.open(ino, flags)
     }
 }
 
 extern fn read_impl(req: fuse_req_t, ino: fuse_ino_t, size: size_t, off: off_t,
                     fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_read) |ops| {
-        unsafe {
-            ops.read(ino, size, off, (*fi).fh)
-        }        
+    let fh = unsafe { (*fi).fh };
+    do run_for_reply(req, OpRead, Some(ino), reply_read) |ops| {
+        ops.read(ino, size, off, fh)
     }
 }
 
 extern fn write_impl(req: fuse_req_t, ino: fuse_ino_t, buf: *u8,
                      size: size_t, off: off_t, fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_write) |ops| {
-        unsafe {
-            do vec::raw::buf_as_slice(buf, size as uint) |vec| {
-                ops.write(ino, vec, off, (*fi).fh, ((*fi).writepage != 0))
-            }
-        }        
+    // The data is in the request buffer, which the C API reuses as soon as
+    // this returns
+    let (data, fh, writepage) = unsafe {
+        (vec::raw::from_buf_raw(buf, size as uint), (*fi).fh,
+         (*fi).writepage != 0)
+    };
+    do run_for_reply(req, OpWrite, Some(ino), reply_write) |ops| {
+        ops.write(ino, data, off, fh, writepage)
     }
 }
 
 extern fn flush_impl(req: fuse_req_t, ino: fuse_ino_t,
                      fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe {
-            ops.flush(ino, (*fi).lock_owner, (*fi).fh)
-        }        
+    let (lock_owner, fh) = unsafe { ((*fi).lock_owner, (*fi).fh) };
+    do run_for_reply(req, OpFlush, Some(ino), reply_zero_err) |ops| {
+        ops.flush(ino, lock_owner, fh)
     }
 }
 
 extern fn release_impl(req: fuse_req_t, ino: fuse_ino_t,
                        fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe {
-            ops.release(ino, (*fi).flags, (*fi).fh)
-        }        
+    let (flags, fh, flush, flock_release) = unsafe {
+        let flock_release = if (*fi).flock_release != 0 {
+            Some((*fi).lock_owner)
+        } else {
+            None
+        };
+        (OpenFlags::from_bits((*fi).flags), (*fi).fh, (*fi).flush != 0,
+         flock_release)
+    };
+    do run_for_reply(req, OpRelease, Some(ino), reply_zero_err) |ops| {
+        ops.release(ino, flags, fh, flush, flock_release)
     }
 }
 
 extern fn fsync_impl(req: fuse_req_t, ino: fuse_ino_t, datasync: c_int,
                      fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe {
-            ops.fsync(ino, (datasync != 0), (*fi).fh)
-        }
+    let fh = unsafe { (*fi).fh };
+    do run_for_reply(req, OpFsync, Some(ino), reply_zero_err) |ops| {
+        ops.fsync(ino, (datasync != 0), fh)
     }
 }
 
 extern fn opendir_impl(req: fuse_req_t, ino: fuse_ino_t,
                        _fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_open) |ops| {
+    do run_for_reply(req, OpOpendir, Some(ino), reply_open) |ops| {
         ops.opendir(ino)
     }
 }
 
 extern fn readdir_impl(req: fuse_req_t, ino: fuse_ino_t, size: size_t,
                        off: off_t, fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_readdir) |ops| {
-        unsafe {
-            ops.readdir(ino, size, off, (*fi).fh)
-        }.and_then(|rr| Ok((size, rr)))
+    let fh = unsafe { (*fi).fh };
+    do run_for_reply(req, OpReaddir, Some(ino), reply_readdir) |ops| {
+        ops.readdir(ino, size, off, fh).and_then(|rr| Ok((size, rr)))
     }
 }
 
 extern fn releasedir_impl(req: fuse_req_t, ino: fuse_ino_t,
                           fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe {
-            ops.releasedir(ino, (*fi).fh)
-        }
+    let fh = unsafe { (*fi).fh };
+    do run_for_reply(req, OpReleasedir, Some(ino), reply_zero_err) |ops| {
+        ops.releasedir(ino, fh)
     }
 }
 
 extern fn fsyncdir_impl(req: fuse_req_t, ino: fuse_ino_t, datasync: c_int,
                         fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe {
-            ops.fsyncdir(ino, (datasync != 0), (*fi).fh)
-        }
+    let fh = unsafe { (*fi).fh };
+    do run_for_reply(req, OpFsyncdir, Some(ino), reply_zero_err) |ops| {
+        ops.fsyncdir(ino, (datasync != 0), fh)
     }
 }
 
 extern fn statfs_impl(req: fuse_req_t, ino: fuse_ino_t) {
-    do run_for_reply(req, reply_statfs) |ops| {
+    do run_for_reply(req, OpStatfs, Some(ino), reply_statfs) |ops| {
         ops.statfs(ino)
     }
 }
 
 extern fn setxattr_impl(req: fuse_req_t, ino: fuse_ino_t, name: *c_schar,
                         value: *u8, size: size_t, flags: c_int) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe {
-            do vec::raw::buf_as_slice(value, size as uint) |vec| {
-                ops.setxattr(ino, &CString::new(name,false), vec, flags)
-            }
-        }
+    // The value is in the same request buffer as the name
+    let (name, value) = unsafe {
+        (name_from_c(name), vec::raw::from_buf_raw(value, size as uint))
+    };
+    do run_for_reply(req, OpSetxattr, Some(ino), reply_zero_err) |ops| {
+        ops.setxattr(ino, &name, value, flags)
     }
 }
 
 extern fn getxattr_impl(req: fuse_req_t, ino: fuse_ino_t, name: *c_schar,
                         size: size_t) {
+    let name = unsafe { name_from_c(name) };
     if size == 0 {
-        do run_for_reply(req, reply_xattr) |ops| {
-            unsafe { ops.getxattr_size(ino, &CString::new(name,false)) }
+        do run_for_reply(req, OpGetxattr, Some(ino), reply_xattr) |ops| {
+            ops.getxattr_size(ino, &name)
         }
     } else {
-        do run_for_reply(req, reply_read) |ops| {
-            unsafe { ops.getxattr(ino, &CString::new(name,false), size) }
+        do run_for_reply(req, OpGetxattr, Some(ino), reply_read) |ops| {
+            ops.getxattr(ino, &name, size)
         }
     }
 }
 
 extern fn listxattr_impl(req: fuse_req_t, ino: fuse_ino_t, size: size_t) {
     if size == 0 {
-        do run_for_reply(req, reply_xattr) |ops| {
+        do run_for_reply(req, OpListxattr, Some(ino), reply_xattr) |ops| {
             ops.listxattr_size(ino)
         }
     } else {
-        do run_for_reply(req, reply_read) |ops| {
+        do run_for_reply(req, OpListxattr, Some(ino), reply_read) |ops| {
             ops.listxattr(ino, size)
         }
     }
 }
 
 extern fn removexattr_impl(req: fuse_req_t, ino: fuse_ino_t, name: *c_schar) {
-    do run_for_reply(req, reply_zero_err) |ops| {
-        unsafe { ops.removexattr(ino, &CString::new(name,false)) }       
+    let name = unsafe { name_from_c(name) };
+    do run_for_reply(req, OpRemovexattr, Some(ino), reply_zero_err) |ops| {
+        ops.removexattr(ino, &name)
     }
 }
 
 extern fn access_impl(req: fuse_req_t,
                       ino: fuse_ino_t, mask: c_int) {
-    do run_for_reply(req, reply_zero_err) |ops| {
+    do run_for_reply(req, OpAccess, Some(ino), reply_zero_err) |ops| {
         ops.access(ino, mask)
     }
 }
 
 extern fn create_impl(req: fuse_req_t, parent: fuse_ino_t, name: *c_schar,
                       mode: mode_t, fi: *Struct_fuse_file_info) {
-    do run_for_reply(req, reply_create) |ops| {
-        unsafe {
-            ops.create(parent, &CString::new(name,false), mode, (*fi).flags)
+    let flags = unsafe { OpenFlags::from_bits((*fi).flags) };
+    let name = unsafe { name_from_c(name) };
+    do run_for_reply(req, OpCreate, Some(parent), reply_create) |ops| {
+        ops.create(parent, &name, Permissions::from_mode(mode), flags)
+    }
+}
+
+// The file info and lock structs live on the C API thread's stack, so copy
+// what we need out of them before handing the request off to another task.
+extern fn getlk_impl(req: fuse_req_t, ino: fuse_ino_t,
+                     fi: *mut Struct_fuse_file_info, lock: *mut Struct_flock) {
+    let (fh, lock_owner, lock) = unsafe {
+        ((*fi).fh, (*fi).lock_owner, flock_to_file_lock(&*lock))
+    };
+    do run_for_reply(req, OpGetlk, Some(ino), reply_lock) |ops| {
+        ops.getlk(ino, fh, lock_owner, &lock)
+    }
+}
+
+extern fn setlk_impl(req: fuse_req_t, ino: fuse_ino_t,
+                     fi: *mut Struct_fuse_file_info, lock: *mut Struct_flock,
+                     sleep: c_int) {
+    let (fh, lock_owner, lock) = unsafe {
+        ((*fi).fh, (*fi).lock_owner, flock_to_file_lock(&*lock))
+    };
+    do run_for_reply(req, OpSetlk, Some(ino), reply_zero_err) |ops| {
+        ops.setlk(ino, fh, lock_owner, &lock, (sleep != 0))
+    }
+}
+
+extern fn flock_impl(req: fuse_req_t, ino: fuse_ino_t,
+                     fi: *mut Struct_fuse_file_info, op: c_int) {
+    let (fh, lock_owner) = unsafe { ((*fi).fh, (*fi).lock_owner) };
+    let flock_op = match op & !LOCK_NB {
+        LOCK_SH => FlockShared,
+        LOCK_EX => FlockExclusive,
+        LOCK_UN => FlockUnlock,
+        _ => {
+            reply_einval(req);
+            return;
+        }
+    };
+    do run_for_reply(req, OpFlock, Some(ino), reply_zero_err) |ops| {
+        ops.flock(ino, fh, lock_owner, flock_op, (op & LOCK_NB != 0))
+    }
+}
+
+extern fn ioctl_impl(req: fuse_req_t, ino: fuse_ino_t, cmd: c_int,
+                     arg: *mut c_void, fi: *mut Struct_fuse_file_info,
+                     flags: c_uint, in_buf: *c_void, in_bufsz: size_t,
+                     out_bufsz: size_t) {
+    // The input buffer belongs to the C API, so it needs copying too
+    let (fh, in_data) = unsafe {
+        ((*fi).fh, vec::raw::from_buf_raw(in_buf as *u8, in_bufsz as uint))
+    };
+    do run_for_reply(req, OpIoctl, Some(ino), reply_ioctl) |ops| {
+        ops.ioctl(ino, cmd, arg as uint as u64, in_data, out_bufsz, flags, fh)
+            .and_then(|reply| Ok((out_bufsz, flags, reply)))
+    }
+}
+
+extern fn poll_impl(req: fuse_req_t, ino: fuse_ino_t,
+                    fi: *mut Struct_fuse_file_info,
+                    ph: *mut Struct_fuse_pollhandle) {
+    let fh = unsafe { (*fi).fh };
+    let handle = Cell::new(if ph.is_null() {
+        None
+    } else {
+        do get_fuse_userdata(req, ()) |userdata, _| {
+            Some(PollHandle{handle: ph, live: userdata.live.clone()})
+        }
+    });
+    do run_for_reply(req, OpPoll, Some(ino), reply_poll) |ops| {
+        ops.poll(ino, fh, handle.take())
+    }
+}
+
+extern fn bmap_impl(req: fuse_req_t, ino: fuse_ino_t, blocksize: size_t,
+                    idx: u64) {
+    do run_for_reply(req, OpBmap, Some(ino), reply_bmap) |ops| {
+        ops.bmap(ino, blocksize, idx)
+    }
+}
+
+extern fn write_buf_impl(req: fuse_req_t, ino: fuse_ino_t,
+                         bufv: *mut Struct_fuse_bufvec, off: off_t,
+                         fi: *mut Struct_fuse_file_info) {
+    let (fh, writepage) = unsafe { ((*fi).fh, ((*fi).writepage != 0)) };
+    match unsafe { buf::take_from_c(bufv) } {
+        Ok(bufv) => {
+            let bufv = Cell::new(bufv);
+            do run_for_reply(req, OpWriteBuf, Some(ino), reply_write) |ops| {
+                ops.write_buf(ino, bufv.take(), off, fh, writepage)
+            }
+        },
+        Err(errno) => send_fuse_reply(Err(errno), req, reply_write)
+    }
+}
+
+extern fn retrieve_reply_impl(req: fuse_req_t, cookie: *mut c_void,
+                              _ino: fuse_ino_t, _offset: off_t,
+                              bufv: *mut Struct_fuse_bufvec) {
+    let chan = do get_fuse_userdata(req, cookie) |userdata, cookie| {
+        do userdata.live.write |live| {
+            take_retrieve(live, cookie)
         }
+    };
+    let data = unsafe { buf::take_from_c(bufv) }.and_then(|b| b.into_bytes());
+    match chan {
+        // Whoever asked may not be waiting any more
+        Some(chan) => { chan.try_send(data); },
+        None => warn!("Reply to a retrieve that isn't outstanding")
+    }
+    reply_none(req, ());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{capabilities_from_bits, capabilities_to_bits, escape_opt,
+                file_lock_to_flock, flock_to_file_lock, split_opts};
+    use ffi::{O_DSYNC, O_SYNC, Struct_flock, F_RDLCK, F_WRLCK};
+    use std::libc::{c_short, off_t, O_APPEND, O_RDONLY, O_RDWR, O_WRONLY,
+                    SEEK_SET};
+
+    fn flock(l_type: c_short, l_start: off_t, l_len: off_t) -> Struct_flock {
+        Struct_flock{l_type: l_type, l_whence: SEEK_SET as c_short,
+                     l_start: l_start, l_len: l_len, l_pid: 42}
+    }
+
+    #[test]
+    fn lock_length_becomes_an_inclusive_end() {
+        let lock = flock_to_file_lock(&flock(F_RDLCK, 10, 5));
+        assert_eq!((lock.start, lock.end), (10, 14));
+        assert_eq!(lock.kind, ReadLock);
+        assert_eq!(lock.pid, 42);
+        let fl = file_lock_to_flock(&lock);
+        assert_eq!((fl.l_start, fl.l_len), (10, 5));
+        assert_eq!(fl.l_type, F_RDLCK);
+    }
+
+    #[test]
+    fn zero_lock_length_runs_to_eof() {
+        let lock = flock_to_file_lock(&flock(F_WRLCK, 100, 0));
+        assert_eq!((lock.start, lock.end), (100, LOCK_TO_EOF));
+        assert_eq!(lock.kind, WriteLock);
+        let fl = file_lock_to_flock(&lock);
+        assert_eq!((fl.l_start, fl.l_len), (100, 0));
+        assert_eq!(fl.l_type, F_WRLCK);
+    }
+
+    #[test]
+    fn access_modes() {
+        let ro = OpenFlags::from_bits(O_RDONLY);
+        assert_eq!(ro.access_mode(), Some(ReadOnly));
+        assert!(ro.is_readable() && !ro.is_writable());
+        let wo = OpenFlags::from_bits(O_WRONLY);
+        assert_eq!(wo.access_mode(), Some(WriteOnly));
+        assert!(!wo.is_readable() && wo.is_writable());
+        let rw = OpenFlags::from_bits(O_RDWR);
+        assert_eq!(rw.access_mode(), Some(ReadWrite));
+        assert!(rw.is_readable() && rw.is_writable());
+    }
+
+    #[test]
+    fn both_access_mode_bits_is_no_access_mode() {
+        let flags = OpenFlags::from_bits(O_RDONLY | O_WRONLY | O_RDWR);
+        assert_eq!(flags.access_mode(), None);
+        assert!(!flags.is_readable() && !flags.is_writable());
+    }
+
+    #[test]
+    fn sync_implies_dsync() {
+        let sync = OpenFlags::from_bits(O_WRONLY | O_SYNC);
+        assert!(sync.sync() && sync.dsync());
+        let dsync = OpenFlags::from_bits(O_WRONLY | O_DSYNC);
+        assert!(dsync.dsync() && !dsync.sync());
+    }
+
+    #[test]
+    fn unknown_bits_leaves_out_known_flags() {
+        let flags = OpenFlags::from_bits(O_RDWR | O_APPEND | O_SYNC |
+                                         0x40000000);
+        assert!(flags.append());
+        assert_eq!(flags.unknown_bits(), 0x40000000);
+        assert_eq!(OpenFlags::from_bits(O_RDWR).unknown_bits(), 0);
+    }
+
+    #[test]
+    fn capabilities_match_the_c_api_bits() {
+        let caps = capabilities_from_bits(1 << 1 | 1 << 10);
+        assert!(caps.contains_elem(CapPosixLocks));
+        assert!(caps.contains_elem(CapFlockLocks));
+        assert!(!caps.contains_elem(CapAsyncRead));
+        assert_eq!(capabilities_to_bits(caps), 1 << 1 | 1 << 10);
+    }
+
+    #[test]
+    fn bits_without_a_capability_are_dropped() {
+        // Bit 2 has never been a FUSE capability
+        let caps = capabilities_from_bits(1 << 0 | 1 << 2 | 1 << 30);
+        assert!(caps.contains_elem(CapAsyncRead));
+        assert_eq!(capabilities_to_bits(caps), 1 << 0);
+    }
+
+    #[test]
+    fn escaped_opts_split_back_apart() {
+        assert_eq!(escape_opt("a,b\\c"), ~"a\\,b\\\\c");
+        assert_eq!(split_opts("ro,fsname=a\\,b\\\\c,debug"),
+                   ~[~"ro", ~"fsname=a,b\\c", ~"debug"]);
+        assert_eq!(split_opts(escape_opt("x,\\,y")), ~[~"x,\\,y"]);
+    }
+
+    #[test]
+    fn options_become_one_escaped_list() {
+        let opts = FuseMountOptions::new(&PosixPath("/mnt/x"))
+            .read_only(true).fsname("a,b").max_read(4096)
+            .raw_arg("-d");
+        assert_eq!(opts.to_args(),
+                   ~[~"rust_fuse", ~"-o", ~"ro,fsname=a\\,b,max_read=4096",
+                     ~"-d", ~"/mnt/x"]);
+        assert_eq!(FuseMountOptions::new(&PosixPath("/mnt/x")).to_args(),
+                   ~[~"rust_fuse", ~"/mnt/x"]);
+    }
+
+    #[test]
+    fn raw_opts_are_found_in_either_form() {
+        let mnt = PosixPath("/mnt/x");
+        assert!(FuseMountOptions::new(&mnt).raw_arg("-o")
+                .raw_arg("ro,nonempty").raw_opt_given("nonempty"));
+        assert!(FuseMountOptions::new(&mnt).raw_arg("-ononempty")
+                .raw_opt_given("nonempty"));
+        // An escaped comma doesn't end the option before it
+        assert!(!FuseMountOptions::new(&mnt).raw_arg("-ofsname=x\\,nonempty")
+                .raw_opt_given("nonempty"));
+        assert!(!FuseMountOptions::new(&mnt).raw_arg("nonempty")
+                .raw_opt_given("nonempty"));
     }
 }
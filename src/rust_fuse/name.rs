@@ -0,0 +1,106 @@
+/*!
+ * File names, as the kernel passes them: a run of bytes with no NUL in it.
+ * Nothing makes them UTF-8, so they're kept as bytes, and `as_utf8` is there
+ * for the filesystems that only deal in UTF-8 names.
+ */
+
+use std::c_str::{CString, ToCStr};
+use std::str;
+
+/// The name of a directory entry, an extended attribute, or the target of a
+/// symlink.  It never contains a NUL byte, and has no terminator.
+#[deriving(Eq, Clone, IterBytes)]
+pub struct FileName {
+    priv bytes: ~[u8]
+}
+
+impl FileName {
+    /// A name made of `bytes`, or None if they contain a NUL
+    pub fn new(bytes: &[u8]) -> Option<FileName> {
+        if bytes.contains(&0u8) {
+            None
+        } else {
+            Some(FileName{bytes: bytes.to_owned()})
+        }
+    }
+
+    /// The UTF-8 bytes of `s` as a name, or None if it contains a NUL
+    pub fn from_str(s: &str) -> Option<FileName> {
+        FileName::new(s.as_bytes())
+    }
+
+    /// The bytes of the name, without a terminator
+    pub fn as_bytes<'a>(&'a self) -> &'a [u8] {
+        self.bytes.as_slice()
+    }
+
+    /// The name as a string, or None if it isn't valid UTF-8
+    pub fn as_utf8<'a>(&'a self) -> Option<&'a str> {
+        if str::is_utf8(self.bytes) {
+            Some(str::from_utf8_slice(self.bytes))
+        } else {
+            None
+        }
+    }
+
+    /// The length of the name in bytes
+    pub fn len(&self) -> uint {
+        self.bytes.len()
+    }
+
+    pub fn eq_bytes(&self, other: &[u8]) -> bool {
+        self.as_bytes() == other
+    }
+
+    pub fn eq_str(&self, other: &str) -> bool {
+        self.eq_bytes(other.as_bytes())
+    }
+}
+
+impl ToCStr for FileName {
+    fn to_c_str(&self) -> CString {
+        self.as_bytes().to_c_str()
+    }
+
+    unsafe fn to_c_str_unchecked(&self) -> CString {
+        self.as_bytes().to_c_str_unchecked()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::c_str::ToCStr;
+
+    #[test]
+    fn names_with_nul_are_rejected() {
+        assert!(FileName::new(bytes!("a", 0, "b")).is_none());
+        assert!(FileName::from_str("a\x00b").is_none());
+    }
+
+    #[test]
+    fn bytes_have_no_terminator() {
+        let name = FileName::from_str("hello").unwrap();
+        assert_eq!(name.as_bytes(), bytes!("hello"));
+        assert_eq!(name.len(), 5);
+        assert!(name.eq_str("hello"));
+        assert!(!name.eq_str("hell"));
+        assert!(name.eq_bytes(bytes!("hello")));
+    }
+
+    #[test]
+    fn non_utf8_names_are_kept() {
+        let name = FileName::new([0x66, 0xff, 0x6f]).unwrap();
+        assert_eq!(name.as_bytes(), &[0x66u8, 0xff, 0x6f]);
+        assert!(name.as_utf8().is_none());
+        assert_eq!(FileName::from_str("fö").unwrap().as_utf8(),
+                   Some("fö"));
+    }
+
+    #[test]
+    fn c_string_has_the_same_bytes() {
+        let name = FileName::from_str("hello").unwrap();
+        let c_str = name.to_c_str();
+        assert_eq!(c_str.as_bytes(), bytes!("hello", 0));
+    }
+}
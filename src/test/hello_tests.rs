@@ -10,13 +10,9 @@ use rust_fuse::lowlevel::*;
 fn hello_fs_works() {
     let tdg = TempDirAutoCleanup::new_opt(&os::tmpdir(),
                                           "hello_fs_works").unwrap();
-    let path_str= tdg.path.to_str();
-    let mount_args = ~["hello_fs".as_bytes().to_owned(),
-                       path_str.as_bytes().to_owned()];
-    // The first argument is for the executable
-    let _mounter = FuseMount::new(~FuseMountOptions{args:mount_args},
+    let _mounter = FuseMount::new(~FuseMountOptions::new(&tdg.path),
                                   ~super::testfs::hello::HelloFs as
-                                  ~FuseLowLevelOps:Send+Freeze);
+                                  ~FuseLowLevelOps:Send+Freeze).unwrap();
     
     let expected_dirs = [~"hello_from_rust"];
     let mut edirs_map = expected_dirs.iter().map(|x| x.clone());
@@ -32,3 +28,29 @@ fn hello_fs_works() {
                                    Open, Read).read_to_end();
     assert_eq!("Hello rusty world!\n", str::from_utf8_slice(file_contents));
 }
+
+#[test]
+fn unmount_works() {
+    let tdg = TempDirAutoCleanup::new_opt(&os::tmpdir(),
+                                          "unmount_works").unwrap();
+    let mut mounter = FuseMount::new(~FuseMountOptions::new(&tdg.path),
+                                     ~super::testfs::hello::HelloFs as
+                                     ~FuseLowLevelOps:Send+Freeze).unwrap();
+    assert!(mounter.is_mounted());
+    assert_eq!(mounter.unmount(), Ok(()));
+    assert!(!mounter.is_mounted());
+    assert_eq!(file::readdir(&tdg.path).unwrap_or(~[]).len(), 0);
+}
+
+#[test]
+fn missing_mount_point_is_an_error() {
+    let tdg = TempDirAutoCleanup::new_opt(&os::tmpdir(),
+                                          "missing_mount_point").unwrap();
+    let result = FuseMount::new(
+        ~FuseMountOptions::new(&tdg.path.push("missing")),
+        ~super::testfs::hello::HelloFs as ~FuseLowLevelOps:Send+Freeze);
+    match result {
+        Err(e) => assert_eq!(e, MountPointMissing),
+        Ok(_) => fail!("Mounted on a mount point that doesn't exist")
+    }
+}